@@ -1,17 +1,21 @@
+use clap::{App, AppSettings, Arg, ArgMatches, ErrorKind, SubCommand};
 use log::{error, info, LevelFilter};
 use log4rs::{
-    append::rolling_file::{
-        policy::compound::{
-            roll::delete::DeleteRoller, trigger::size::SizeTrigger, CompoundPolicy,
+    append::{
+        console::{ConsoleAppender, Target},
+        rolling_file::{
+            policy::compound::{
+                roll::delete::DeleteRoller, trigger::size::SizeTrigger, CompoundPolicy,
+            },
+            RollingFileAppender,
         },
-        RollingFileAppender,
     },
     config::{Appender, Config, Root},
 };
-use reqwest::StatusCode;
 use std::{
     env,
     io::{self, Read},
+    path::{Path, PathBuf},
     process,
     sync::mpsc,
     thread,
@@ -19,44 +23,251 @@ use std::{
 };
 
 use activity_insights_cli::{
-    build_pulses, constants, get_libraries, maybe_update, open_browser, register, send_pulses,
-    Credentials,
+    build_profile_pulses, constants, doctor, get_libraries, inspect_events, maybe_update,
+    permissions, prompt, pulse_deadline, pulse_request_bodies, register, register_with_loopback,
+    revoke_tos, rotate_api_token, send_pulses, show_url, unregister, version,
+    ActivityInsightsError, Config as CliConfig, Credentials, Dirs, Moved, ProfileSelector,
+    TosAcceptance, TosChange,
 };
 
 fn main() {
-    create_logger();
+    let matches = parse_args();
+    let (command, command_matches) = matches.subcommand();
+    // Global options can come before or after the command
+    let option = |name| {
+        command_matches
+            .and_then(|m| m.value_of(name))
+            .or_else(|| matches.value_of(name))
+    };
+    let flag = |name: &str| command_matches.is_some_and(|m| m.is_present(name));
+
+    if let Some(config) = option("config") {
+        CliConfig::use_file(PathBuf::from(config));
+    }
+
+    let dirs = Dirs::fetch().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(10);
+    });
+    // Before the logger opens the log file, which may be one of the files moved
+    let moved = dirs.migrate_legacy();
+    create_logger(
+        &dirs.state,
+        flag("verbose") || matches.is_present("verbose"),
+    );
     info!("Starting cli...");
+    log_migration(&moved);
+
+    // Before a profile is picked so a config that doesn't parse can still be fixed
+    if let ("config", Some(config_matches)) = (command, command_matches) {
+        config_command(config_matches);
+        return;
+    }
 
-    match env::args().nth(1) {
-        Some(v) if v.as_str() == "accept_tos" => accept_tos_command(),
-        Some(v) if v.as_str() == "version" => println!("{}", constants::VERSION),
-        Some(v) if v.as_str() == "libraries" => get_libraries_command(),
+    match command {
+        // These don't use a profile, so they keep working when the config doesn't parse
+        "version" => println!("{}", constants::VERSION),
+        "libraries" => get_libraries_command(),
+        "inspect" => inspect_command(),
         _ => {
-            check_tos();
-            match env::args().nth(1) {
-                Some(v) if v.as_str() == "register" => register_command(),
-                Some(v) if v.as_str() == "dashboard" => dashboard_command(),
-                _ => pulse_command(),
-            }
+            let selector = ProfileSelector::fetch(option("profile").map(String::from))
+                .unwrap_or_else(|e| {
+                    error!("Unable to select a profile: {}", e);
+                    eprintln!("{}", e);
+                    exit(104)
+                });
+            profile_command(command, &selector, &dirs, flag)
         }
     };
 
+    // Plugin developers use these to see what would be sent, so they stay off the network and
+    // leave the binary alone
+    if command == "inspect" || (command == "pulse" && flag("dry-run")) {
+        return;
+    }
+
     if let Err(e) = maybe_update() {
         error!("Error updating: {}", e)
     }
 }
 
+fn profile_command(
+    command: &str,
+    selector: &ProfileSelector,
+    dirs: &Dirs,
+    flag: impl Fn(&str) -> bool,
+) {
+    let profile = selector.selected();
+    match command {
+        "accept_tos" => accept_tos_command(profile),
+        "revoke_tos" => revoke_tos_command(profile),
+        "tos" => tos_command(profile, flag("show")),
+        "doctor" => doctor_command(dirs, profile),
+        // Nothing is sent, so neither the TOS nor credentials are needed
+        "pulse" if flag("dry-run") => pulse_command(selector, true),
+        "unregister" => unregister_command(profile),
+        _ => {
+            check_tos(profile);
+            match command {
+                "register" => register_command(profile, flag("loopback"), flag("qr")),
+                "rotate-token" => rotate_token_command(profile, flag("qr")),
+                "dashboard" => {
+                    check_credentials(profile);
+                    check_api_token(profile);
+                    dashboard_command()
+                }
+                _ => {
+                    check_credentials(profile);
+                    pulse_command(selector, false)
+                }
+            }
+        }
+    }
+}
+
+/// Editors run the cli without a command to send pulses, so that stays the default
+fn cli() -> App<'static, 'static> {
+    App::new("activity-insights")
+        .version(constants::VERSION)
+        .about("Tracks coding activity for Pluralsight Activity Insights. With no command, editor events are read from stdin and sent as pulses")
+        .settings(&[AppSettings::VersionlessSubcommands, AppSettings::DisableHelpSubcommand])
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .value_name("NAME")
+                .global(true)
+                .help("Profile to use instead of the ones picked by the config"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("FILE")
+                .global(true)
+                .help("Config file to use instead of the default one"),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .long("verbose")
+                .short("v")
+                .global(true)
+                .help("Logs debug messages and copies the log to stderr"),
+        )
+        .subcommand(
+            SubCommand::with_name("pulse")
+                .about("Sends the editor events read from stdin")
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Prints the body of each request instead of sending it"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("inspect")
+                .about("Prints the language and libraries detected for each editor event read from stdin"),
+        )
+        .subcommand(SubCommand::with_name("accept_tos").about("Accepts the terms of service"))
+        .subcommand(
+            SubCommand::with_name("revoke_tos")
+                .about("Revokes the terms of service, stopping collection and deleting pending activity"),
+        )
+        .subcommand(
+            SubCommand::with_name("tos")
+                .about("Prints whether the terms of service were accepted")
+                .arg(
+                    Arg::with_name("show")
+                        .long("show")
+                        .help("Also prints the terms and what changed since they were accepted"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("register")
+                .about("Registers the cli with a Pluralsight account in the browser")
+                .arg(
+                    Arg::with_name("loopback")
+                        .long("loopback")
+                        .help("Waits for the browser to confirm the registration"),
+                )
+                .arg(qr_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("rotate-token")
+                .about("Registers a new api token and revokes the old one")
+                .arg(qr_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("unregister")
+                .alias("logout")
+                .about("Revokes the api token and deletes pending activity"),
+        )
+        .subcommand(SubCommand::with_name("dashboard").about("Opens the Activity Insights dashboard"))
+        .subcommand(
+            SubCommand::with_name("libraries")
+                .about("Prints the libraries found in the code read from stdin"),
+        )
+        .subcommand(
+            SubCommand::with_name("doctor")
+                .alias("status")
+                .about("Checks everything tracking depends on and fixes loose permissions"),
+        )
+        .subcommand(SubCommand::with_name("version").about("Prints the version of the cli"))
+        .subcommand(
+            SubCommand::with_name("config")
+                .about("Reads and edits the settings in the config file")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("get")
+                        .about("Prints the effective value of a setting")
+                        .arg(Arg::with_name("key").required(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("set")
+                        .about("Writes a setting to the config file")
+                        .arg(Arg::with_name("key").required(true))
+                        .arg(Arg::with_name("value").required(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("unset")
+                        .about("Removes a setting from the config file so the default is used")
+                        .arg(Arg::with_name("key").required(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("Prints every setting and where its value comes from"),
+                ),
+        )
+}
+
+fn qr_arg() -> Arg<'static, 'static> {
+    Arg::with_name("qr")
+        .long("qr")
+        .help("Shows a QR code of the url when it can't be opened in a browser")
+}
+
+/// Usage errors exit with USAGE_EXIT_CODE rather than being sent as a pulse
+fn parse_args() -> ArgMatches<'static> {
+    cli().get_matches_safe().unwrap_or_else(|e| match e.kind {
+        ErrorKind::HelpDisplayed | ErrorKind::VersionDisplayed => {
+            println!("{}", e.message);
+            process::exit(0)
+        }
+        _ => {
+            eprintln!("{}", e.message);
+            process::exit(constants::USAGE_EXIT_CODE)
+        }
+    })
+}
+
 /*
  * Create_logger will exit if it can't create the logger
  * If the process exits while creating the logger, the exit code will be in the range 10-19
  */
-fn create_logger() {
-    let mut log_dir = dirs::home_dir().unwrap_or_else(|| {
-        eprintln!("Error finding home dir");
-        exit(10);
+fn create_logger(log_dir: &Path, verbose: bool) {
+    // The appender would create the directory with the default permissions otherwise
+    permissions::create_private_dir(log_dir).unwrap_or_else(|e| {
+        eprintln!("Can't create {:?}: {}", log_dir, e);
+        exit(14);
     });
-    log_dir.push(constants::PS_DIR);
-    log_dir.push(constants::LOG_FILE);
+    let log_file = log_dir.join(constants::LOG_FILE);
 
     let rotation_policy = CompoundPolicy::new(
         Box::new(SizeTrigger::new(10_000)),
@@ -64,19 +275,30 @@ fn create_logger() {
     );
 
     let logger = RollingFileAppender::builder()
-        .build(log_dir, Box::new(rotation_policy))
+        .build(log_file, Box::new(rotation_policy))
         .unwrap_or_else(|e| {
             eprintln!("Can't create the log file: {}", e);
             exit(11);
         });
 
-    let config = Config::builder()
-        .appender(Appender::builder().build("logger", Box::new(logger)))
-        .build(Root::builder().appender("logger").build(LevelFilter::Info))
-        .unwrap_or_else(|e| {
-            eprintln!("Can't create the logger config: {}", e);
-            exit(12);
-        });
+    let mut config =
+        Config::builder().appender(Appender::builder().build("logger", Box::new(logger)));
+    let mut root = Root::builder().appender("logger");
+    if verbose {
+        let stderr = ConsoleAppender::builder().target(Target::Stderr).build();
+        config = config.appender(Appender::builder().build("stderr", Box::new(stderr)));
+        root = root.appender("stderr");
+    }
+    let level = if verbose {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    };
+
+    let config = config.build(root.build(level)).unwrap_or_else(|e| {
+        eprintln!("Can't create the logger config: {}", e);
+        exit(12);
+    });
 
     log4rs::init_config(config).unwrap_or_else(|e| {
         eprintln!("Failed to initialize logger: {}", e);
@@ -84,23 +306,130 @@ fn create_logger() {
     });
 }
 
-fn check_tos() {
-    let creds = Credentials::fetch().unwrap_or_else(|e| {
+fn log_migration(moved: &[Moved]) {
+    for Moved { from, to, result } in moved {
+        match result {
+            Ok(()) => info!("Moved {:?} to {:?}", from, to),
+            Err(e) => error!("Unable to move {:?} to {:?}: {}", from, to, e),
+        }
+    }
+}
+
+fn fetch_credentials(profile: Option<&str>) -> Credentials {
+    Credentials::fetch_profile(profile).unwrap_or_else(|e| {
         error!("Unable to get creds file: {}", e);
         exit(101)
+    })
+}
+
+/// A revoked TOS isn't shown again, editors tell the user collection is off instead of prompting.
+/// At a terminal the user is asked to accept the TOS on the spot rather than having it printed.
+fn check_tos(profile: Option<&str>) {
+    let mut creds = fetch_credentials(profile);
+
+    if creds.tos_revoked_at().is_some() {
+        println!("{}", constants::TOS_REVOKED_MESSAGE);
+        exit(constants::TOS_REVOKED_EXIT_CODE)
+    }
+
+    if !creds.has_accepted_latest(&version::tos_version()) {
+        if prompt::is_interactive() {
+            prompt_tos(&mut creds);
+        } else {
+            println!("{}", constants::TOS);
+            exit(constants::NOT_ACCEPTED_TOS_EXIT_CODE)
+        }
+    }
+}
+
+fn prompt_tos(creds: &mut Credentials) {
+    prompt::page(constants::TOS);
+    let accepted = prompt::confirm(
+        "Do you accept the terms of service?",
+        &mut io::stdin().lock(),
+        &mut io::stdout(),
+    )
+    .unwrap_or_else(|e| {
+        error!("Error prompting for the TOS: {}", e);
+        exit(constants::NOT_ACCEPTED_TOS_EXIT_CODE)
     });
 
-    if !creds.has_accepted_latest(constants::TOS_VERSION) {
-        println!("{}", constants::TOS);
+    if !accepted {
         exit(constants::NOT_ACCEPTED_TOS_EXIT_CODE)
     }
+
+    creds
+        .accept_tos(TosAcceptance::bundled())
+        .unwrap_or_else(|e| {
+            error!("Error accepting TOS {}: {}", constants::TOS_VERSION, e);
+            exit(102)
+        });
 }
 
-fn register_command() {
+/// A corrupted credentials file is backed up and reset when it's read. When the api token couldn't
+/// be recovered from it, this exits with CORRUPT_CREDENTIALS_EXIT_CODE (103) and
+/// CORRUPT_CREDENTIALS_MESSAGE on stdout.
+fn check_credentials(profile: Option<&str>) {
+    let creds = fetch_credentials(profile);
+
+    if let Err(e) = creds.check_integrity() {
+        error!("{}", e);
+        println!("{}", constants::CORRUPT_CREDENTIALS_MESSAGE);
+        exit(constants::CORRUPT_CREDENTIALS_EXIT_CODE);
+    }
+}
+
+/// Editors look for the exit code and the json message on stdout to prompt the user to register
+/// again
+fn check_api_token(profile: Option<&str>) {
+    let creds = fetch_credentials(profile);
+
+    if creds.api_token_rejected() {
+        exit_api_token_rejected();
+    }
+}
+
+fn exit_api_token_rejected() -> ! {
+    println!("{}", constants::TOKEN_REJECTED_MESSAGE);
+    exit(constants::TOKEN_REJECTED_EXIT_CODE)
+}
+
+/// Editors look for the exit code and the json message on stdout to have the user accept the TOS
+/// for `profile`, which is None for the default profile
+fn exit_profile_tos_not_accepted(profile: Option<&str>) -> ! {
+    let message = serde_json::json!({
+        "error": "tos_not_accepted",
+        "message": format!(
+            "Accept the terms of service for the {} profile",
+            profile.unwrap_or("default")
+        ),
+        "command": "accept_tos",
+        "profile": profile,
+    });
+    println!("{}", message);
+    exit(constants::PROFILE_TOS_NOT_ACCEPTED_EXIT_CODE)
+}
+
+fn register_command(profile: Option<&str>, loopback: bool, qr_code: bool) {
     info!("Starting register command");
-    if let Err(e) = register() {
+    if loopback {
+        match register_with_loopback(profile, qr_code) {
+            Ok(registered_at) => {
+                info!("Registration confirmed at {}", registered_at);
+                println!("Registered Activity Insights");
+            }
+            Err(e) => {
+                error!("Error on registration: {}", e);
+                eprintln!("Registration failed: {}", e);
+                exit(33);
+            }
+        }
+        return;
+    }
+
+    if let Err(e) = register(profile, qr_code) {
         error!("Error on registration: {}", e);
-        if let Err(e) = open_browser(constants::BAD_REGISTRATION_URL) {
+        if let Err(e) = show_url(constants::BAD_REGISTRATION_URL, false) {
             error!(
                 "Error trying to let the user know a registration went bad: {}",
                 e
@@ -110,9 +439,88 @@ fn register_command() {
     }
 }
 
+fn unregister_command(profile: Option<&str>) {
+    info!("Starting unregister command");
+    if env::var(constants::TOKEN_ENV_VAR).is_ok() {
+        eprintln!(
+            "The api token in {} is left alone, unset it to stop using it",
+            constants::TOKEN_ENV_VAR
+        );
+    }
+
+    if let Err(e) = unregister(profile) {
+        error!("Error unregistering: {}", e);
+        eprintln!("Unable to unregister: {}", e);
+        exit(60);
+    }
+    println!("Unregistered Activity Insights");
+}
+
+fn rotate_token_command(profile: Option<&str>, qr_code: bool) {
+    info!("Starting rotate-token command");
+    if let Err(e) = rotate_api_token(profile, qr_code) {
+        error!("Error rotating the api token: {}", e);
+        eprintln!("Unable to rotate the api token: {}", e);
+        exit(32);
+    }
+}
+
+/// Looks for problems with the local setup and fixes the ones it can
+fn doctor_command(dirs: &Dirs, profile: Option<&str>) {
+    info!("Starting doctor command");
+    let checks = doctor::run(dirs, profile);
+    for check in &checks {
+        println!("[{}] {}: {}", check.status, check.name, check.detail);
+    }
+
+    if checks
+        .iter()
+        .any(|check| check.status == doctor::Status::Fail)
+    {
+        exit(71);
+    }
+}
+
+fn config_command(matches: &ArgMatches) {
+    info!("Starting config command");
+    let path = CliConfig::path().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(80)
+    });
+    let (command, command_matches) = matches.subcommand();
+    let arg = |name| {
+        command_matches
+            .and_then(|m| m.value_of(name))
+            .unwrap_or_default()
+    };
+
+    let result = match command {
+        "get" => CliConfig::get(&path, arg("key")).map(|setting| println!("{}", setting.value)),
+        "set" => CliConfig::set(&path, arg("key"), arg("value")),
+        "unset" => CliConfig::unset(&path, arg("key")),
+        _ => CliConfig::list(&path).map(|settings| {
+            for setting in settings {
+                match setting.when_unset {
+                    Some(when_unset) => println!(
+                        "{} = {} ({}, {})",
+                        setting.key, setting.value, setting.source, when_unset
+                    ),
+                    None => println!("{} = {} ({})", setting.key, setting.value, setting.source),
+                }
+            }
+        }),
+    };
+
+    if let Err(e) = result {
+        error!("Error running config {}: {}", command, e);
+        eprintln!("{}", e);
+        exit(80)
+    }
+}
+
 fn dashboard_command() {
     info!("Starting dashboard command");
-    if let Err(e) = open_browser(constants::DASHBOARD_URL) {
+    if let Err(e) = show_url(constants::DASHBOARD_URL, false) {
         error!("Error trying to show the user their dashboard: {}", e);
         exit(40);
     } else {
@@ -120,7 +528,9 @@ fn dashboard_command() {
     }
 }
 
-fn pulse_command() {
+/// A dry run prints the request bodies as json lines, with the pulses of each profile sent
+/// separately
+fn pulse_command(selector: &ProfileSelector, dry_run: bool) {
     info!("Starting pulse command");
 
     let input = match read_from_stdin_with_timeout(Duration::from_millis(10_000)) {
@@ -131,37 +541,191 @@ fn pulse_command() {
         }
     };
 
-    let pulses = build_pulses(&input).unwrap_or_else(|e| {
+    let profile_pulses = build_profile_pulses(&input, selector).unwrap_or_else(|e| {
         error!("Error building pulses from content: {}\n{}", input, e);
         exit(22);
     });
 
-    match send_pulses(&pulses) {
-        Ok(StatusCode::NO_CONTENT) => {
-            info!("Pulses successfully sent");
+    if dry_run {
+        for (profile, pulses) in &profile_pulses {
+            let bodies = pulse_request_bodies(pulses).unwrap_or_else(|e| {
+                error!("Error serializing pulses: {}", e);
+                exit(24);
+            });
+            info!(
+                "Dry run, {} requests for profile {}",
+                bodies.len(),
+                profile.as_deref().unwrap_or("default")
+            );
+            bodies.iter().for_each(|body| println!("{}", body));
         }
-        Ok(code) => info!("Unexpected status code for pulses: {:?}\n{}", pulses, code),
-        Err(e) => {
-            error!("Error sending pulses:{:?}\n{}", pulses, e);
-            exit(23);
+        return;
+    }
+
+    // Every profile gets its pulses sent before exiting. A rejected token takes priority over
+    // other failures since the editor has to act on it, then profiles picked by a directory rule
+    // that haven't accepted the TOS yet.
+    let mut rejected = false;
+    let mut not_accepted = Vec::new();
+    let mut failed = false;
+    let deadline = pulse_deadline();
+    for (profile, pulses) in &profile_pulses {
+        let name = profile.as_deref().unwrap_or("default");
+        match send_pulses(pulses, profile.as_deref(), deadline) {
+            Ok(report) if report.all_sent() => {
+                info!("Pulses successfully sent for profile {}", name);
+            }
+            Ok(report) if report.queued() > 0 => {
+                error!(
+                    "{} pulses for profile {} were queued to send later",
+                    report.queued(),
+                    name
+                );
+                failed = true;
+            }
+            Ok(report) => info!(
+                "Not every chunk of pulses for profile {} was accepted: {:?}",
+                name, report
+            ),
+            Err(ActivityInsightsError::TosRevoked) => {
+                info!(
+                    "Dropped the pulses for profile {}, it revoked the TOS",
+                    name
+                );
+            }
+            Err(ActivityInsightsError::TosNotAccepted) => {
+                info!(
+                    "Profile {} hasn't accepted the TOS, pulses were queued until it does",
+                    name
+                );
+                not_accepted.push(profile.as_deref());
+            }
+            Err(ActivityInsightsError::ApiTokenRejected) => {
+                error!(
+                    "Api token for profile {} was rejected, pulses were queued until the user registers again",
+                    name
+                );
+                rejected = true;
+            }
+            Err(e) => {
+                error!(
+                    "Error sending pulses for profile {}:{:?}\n{}",
+                    name, pulses, e
+                );
+                failed = true;
+            }
         }
     }
+
+    if rejected {
+        exit_api_token_rejected();
+    } else if let Some(profile) = not_accepted.first() {
+        exit_profile_tos_not_accepted(*profile);
+    } else if failed {
+        exit(23);
+    }
 }
 
-fn accept_tos_command() {
-    let mut creds = Credentials::fetch().unwrap_or_else(|e| {
-        error!("Unable to get creds file: {}", e);
-        exit(101)
-    });
+fn accept_tos_command(profile: Option<&str>) {
+    let mut creds = fetch_credentials(profile);
 
     creds
-        .accept_tos(constants::TOS_VERSION)
+        .accept_tos(TosAcceptance::bundled())
         .unwrap_or_else(|e| {
             error!("Error accepting TOS {}: {}", constants::TOS_VERSION, e);
             exit(102)
         });
 }
 
+fn revoke_tos_command(profile: Option<&str>) {
+    info!("Starting revoke_tos command");
+    revoke_tos(profile).unwrap_or_else(|e| {
+        error!("Error revoking the TOS: {}", e);
+        exit(106)
+    });
+    println!("Activity Insights is off and the pending activity was deleted. Run accept_tos to turn it back on");
+}
+
+/// Without `--show` only the acceptance is printed
+fn tos_command(profile: Option<&str>, show: bool) {
+    let creds = fetch_credentials(profile);
+    let acceptance = creds.tos_acceptance();
+
+    if show {
+        println!("{}\n", constants::TOS);
+    }
+
+    println!("Terms of service version {}", constants::TOS_VERSION);
+    match (acceptance, creds.tos_revoked_at()) {
+        (Some(acceptance), _) => {
+            print!("Accepted version {}", acceptance.version);
+            if let Some(at) = acceptance.accepted_at {
+                print!(" on {}", at.to_rfc2822());
+            }
+            if let Some(cli_version) = &acceptance.cli_version {
+                print!(" with cli version {}", cli_version);
+            }
+            println!();
+        }
+        (None, Some(at)) => println!("Revoked on {}", at.to_rfc2822()),
+        (None, None) => println!("Not accepted"),
+    }
+
+    if !show {
+        return;
+    }
+
+    let changes = TosChange::since(acceptance.map(|acceptance| &acceptance.version));
+    if acceptance.is_some_and(TosAcceptance::text_changed) {
+        println!(
+            "\nThe wording of version {} changed since it was accepted",
+            constants::TOS_VERSION
+        );
+    }
+    if !changes.is_empty() {
+        println!("\nChanges since the accepted version:");
+        for change in changes {
+            println!("  {}: {}", change.version, change.summary);
+        }
+    }
+}
+
+fn inspect_command() {
+    info!("Starting inspect command");
+    let input = match read_from_stdin_with_timeout(Duration::from_millis(10_000)) {
+        Ok(input) => input,
+        Err(e) => {
+            error!("Timedout reading from stdin: {}", e);
+            exit(90);
+        }
+    };
+
+    let inspections = inspect_events(&input).unwrap_or_else(|e| {
+        eprintln!("The input isn't a json array of editor events: {}", e);
+        exit(91);
+    });
+    for (i, inspection) in inspections.iter().enumerate() {
+        let event = match &inspection.file_path {
+            Some(path) => path.display().to_string(),
+            None => format!("event {}", i + 1),
+        };
+        match (&inspection.error, &inspection.language) {
+            (Some(e), _) => println!("{}: error: {}", event, e),
+            (None, language) if inspection.libraries.is_empty() => println!(
+                "{}: {}, no libraries",
+                event,
+                language.as_deref().unwrap_or_default()
+            ),
+            (None, language) => println!(
+                "{}: {}, libraries: {}",
+                event,
+                language.as_deref().unwrap_or_default(),
+                inspection.libraries.join(", ")
+            ),
+        }
+    }
+}
+
 fn get_libraries_command() {
     let content = match read_from_stdin_with_timeout(Duration::from_millis(10_000)) {
         Ok(input) => input,
@@ -0,0 +1,155 @@
+use log::warn;
+use qrcode::{render::unicode::Dense1x2, QrCode};
+use std::{
+    env,
+    ffi::OsString,
+    io,
+    process::{Child, Command},
+};
+
+/// How a url ended up in front of the user
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shown {
+    Browser,
+    /// Printed on stderr for the user to open somewhere else
+    Printed,
+}
+
+/// Opens `url` with $BROWSER if it's set, or the platform's default browser. Over ssh or without a
+/// display there's no browser to open, so the url is printed instead, along with a QR code for
+/// opening it on a phone if `qr_code` is set. The url is also printed when the browser, including
+/// one from $BROWSER, can't be started.
+pub fn show_url(url: &str, qr_code: bool) -> Result<Shown, io::Error> {
+    if let Some((program, args)) = env::var("BROWSER")
+        .ok()
+        .and_then(|browser| browser_command(&browser, url))
+    {
+        match Command::new(&program).args(args).spawn() {
+            Ok(_) => return Ok(Shown::Browser),
+            Err(e) => {
+                warn!(
+                    "Unable to start $BROWSER ({}), printing the url instead: {}",
+                    program, e
+                );
+                print_url(url, qr_code);
+                return Ok(Shown::Printed);
+            }
+        }
+    }
+
+    if is_headless(|name| env::var_os(name)) {
+        print_url(url, qr_code);
+        return Ok(Shown::Printed);
+    }
+
+    match open_browser(url) {
+        Ok(_) => Ok(Shown::Browser),
+        Err(e) => {
+            warn!(
+                "Unable to open the browser, printing the url instead: {}",
+                e
+            );
+            print_url(url, qr_code);
+            Ok(Shown::Printed)
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn open_browser(url: &str) -> Result<Child, io::Error> {
+    Command::new("open").args(&[url]).spawn()
+}
+
+#[cfg(target_os = "linux")]
+pub fn open_browser(url: &str) -> Result<Child, io::Error> {
+    Command::new("xdg-open").args(&[url]).spawn()
+}
+
+#[cfg(target_os = "windows")]
+pub fn open_browser(url: &str) -> Result<Child, io::Error> {
+    Command::new("cmd").args(&["/C", "start", url]).spawn()
+}
+
+/// A session over ssh, or on linux one without an X or Wayland display
+fn is_headless(var: impl Fn(&str) -> Option<OsString>) -> bool {
+    let set = |name| var(name).is_some_and(|value| !value.is_empty());
+    if set("SSH_CONNECTION") || set("SSH_TTY") {
+        return true;
+    }
+
+    cfg!(target_os = "linux") && !set("DISPLAY") && !set("WAYLAND_DISPLAY")
+}
+
+/// $BROWSER is a list of commands separated by colons, the first one is used. `%s` in a command is
+/// replaced with the url, otherwise the url is added as the last argument.
+fn browser_command(browser: &str, url: &str) -> Option<(String, Vec<String>)> {
+    let command = browser
+        .split(':')
+        .find(|command| !command.trim().is_empty())?;
+    let mut words = command.split_whitespace().map(String::from);
+    let program = words.next()?;
+    let mut args: Vec<String> = words.collect();
+
+    if args.iter().any(|arg| arg.contains("%s")) {
+        args.iter_mut()
+            .for_each(|arg| *arg = arg.replace("%s", url));
+    } else {
+        args.push(url.to_string());
+    }
+    Some((program, args))
+}
+
+fn print_url(url: &str, qr_code: bool) {
+    eprintln!("Open this url in a browser to continue:\n\n    {}\n", url);
+
+    if qr_code {
+        match QrCode::new(url) {
+            Ok(code) => eprintln!("{}", code.render::<Dense1x2>().quiet_zone(true).build()),
+            Err(e) => warn!("Unable to render the url as a QR code: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headless_sessions() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(key, _)| *key == name)
+                    .map(|(_, value)| OsString::from(value))
+            }
+        };
+
+        assert!(is_headless(env(&[
+            ("SSH_CONNECTION", "10.0.0.2 50000 10.0.0.1 22"),
+            ("DISPLAY", ":0")
+        ])));
+        assert!(!is_headless(env(&[("DISPLAY", ":0")])));
+        assert!(!is_headless(env(&[("WAYLAND_DISPLAY", "wayland-0")])));
+        assert_eq!(
+            is_headless(env(&[("DISPLAY", "")])),
+            cfg!(target_os = "linux")
+        );
+    }
+
+    #[test]
+    fn browser_commands() {
+        let url = "https://example.com";
+        assert_eq!(
+            browser_command("w3m", url),
+            Some((String::from("w3m"), vec![String::from(url)]))
+        );
+        assert_eq!(
+            browser_command(":firefox --new-tab %s:chromium", url),
+            Some((
+                String::from("firefox"),
+                vec![String::from("--new-tab"), String::from(url)]
+            ))
+        );
+        assert_eq!(browser_command(" : ", url), None);
+    }
+}
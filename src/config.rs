@@ -0,0 +1,214 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+use thiserror::Error;
+
+use crate::{constants, paths::Dirs, ActivityInsightsError};
+
+mod settings;
+
+pub use settings::{Effective, Source};
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Error deserializing the config file: {0}")]
+    DeserializationError(#[from] toml::de::Error),
+
+    #[error("Error parsing the config file: {0}")]
+    ParseError(#[from] toml_edit::TomlError),
+
+    #[error("Invalid proxy url {0}: {1}")]
+    InvalidProxy(String, String),
+
+    #[error("Invalid CA certificate {0}: {1}")]
+    InvalidCertificate(PathBuf, reqwest::Error),
+
+    #[error("Unknown config key {0}, the keys are {1}")]
+    UnknownKey(String, String),
+
+    #[error("Invalid value {value:?} for {key}, expected {expected}")]
+    InvalidValue {
+        key: &'static str,
+        value: String,
+        expected: String,
+    },
+}
+
+/// Settings for the cli. Unlike the credentials, these are written by the user so a missing file
+/// just means everything is left at its default.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    pub credentials: CredentialsConfig,
+    pub http: HttpConfig,
+    /// Rules for which profile pulses are sent under, by the directory the file is in
+    pub profiles: Vec<ProfileRule>,
+    pub pulses: PulsesConfig,
+    pub updates: UpdatesConfig,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CredentialsConfig {
+    pub backend: CredentialsBackend,
+    /// Key for the encrypted backend. Without one the passphrase is read from
+    /// ACTIVITY_INSIGHTS_PASSPHRASE
+    pub key_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CredentialsBackend {
+    #[default]
+    File,
+    Encrypted,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// Proxy used for every request, e.g. http://proxy.corp.example:8080
+    pub proxy: Option<String>,
+    /// Hosts that bypass the proxy. An entry matches the host itself and all of its subdomains,
+    /// `*` matches everything.
+    pub no_proxy: Vec<String>,
+    /// PEM files with root certificates to trust on top of the system ones
+    pub ca_certificates: Vec<PathBuf>,
+    pub connect_timeout_secs: Option<u64>,
+    pub request_timeout_secs: Option<u64>,
+    pub download_timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ProfileRule {
+    pub profile: String,
+    /// A leading ~ is the home directory
+    pub directories: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PulsesConfig {
+    /// Gzip pulse uploads. Left unset, pulses are compressed once the api says it accepts gzip
+    pub gzip: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct UpdatesConfig {
+    /// Install recommended updates on their own, which is the default. Updates the api requires
+    /// are installed either way.
+    pub auto: Option<bool>,
+}
+
+/// The file given with --config, read in place of the one in the config directory
+static CONFIG_FILE: OnceLock<PathBuf> = OnceLock::new();
+
+impl Config {
+    /// Reads the config from `path` for the rest of the run. Only the first call has any effect
+    pub fn use_file(path: PathBuf) {
+        if CONFIG_FILE.set(path).is_err() {
+            warn!("The config file was already chosen");
+        }
+    }
+
+    /// A config file given with --config has to exist, the default one is optional
+    pub fn fetch() -> Result<Self, ActivityInsightsError> {
+        match CONFIG_FILE.get() {
+            Some(path) => Self::fetch_from_file(path, true),
+            None => Self::fetch_from_dir(&Dirs::fetch()?.config),
+        }
+    }
+
+    /// The file the config is read from, whether or not it exists
+    pub fn path() -> Result<PathBuf, ActivityInsightsError> {
+        match CONFIG_FILE.get() {
+            Some(path) => Ok(path.clone()),
+            None => Ok(Dirs::fetch()?.config.join(constants::CONFIG_FILE_NAME)),
+        }
+    }
+
+    pub fn list(path: &Path) -> Result<Vec<Effective>, ActivityInsightsError> {
+        settings::list(path)
+    }
+
+    pub fn get(path: &Path, key: &str) -> Result<Effective, ActivityInsightsError> {
+        settings::get(path, key)
+    }
+
+    pub fn set(path: &Path, key: &str, value: &str) -> Result<(), ActivityInsightsError> {
+        settings::set(path, key, value)
+    }
+
+    pub fn unset(path: &Path, key: &str) -> Result<(), ActivityInsightsError> {
+        settings::unset(path, key)
+    }
+
+    fn fetch_from_dir(dir: &Path) -> Result<Self, ActivityInsightsError> {
+        Self::fetch_from_file(&dir.join(constants::CONFIG_FILE_NAME), false)
+    }
+
+    fn fetch_from_file(path: &Path, required: bool) -> Result<Self, ActivityInsightsError> {
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(toml::from_str(&content).map_err(ConfigError::from)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => Ok(Config::default()),
+            Err(e) => Err(ActivityInsightsError::IO(path.to_path_buf(), e)),
+        }
+    }
+}
+
+impl HttpConfig {
+    /// no_proxy entries follow the usual NO_PROXY conventions: a leading dot is ignored and a
+    /// bare domain also covers its subdomains
+    pub fn bypasses_proxy(&self, host: &str) -> bool {
+        self.no_proxy.iter().any(|entry| {
+            let entry = entry.trim().trim_start_matches('.');
+            entry == "*"
+                || host.eq_ignore_ascii_case(entry)
+                || host
+                    .to_ascii_lowercase()
+                    .ends_with(&format!(".{}", entry.to_ascii_lowercase()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn missing_config_is_default() {
+        let fake_dir = tempdir().unwrap();
+        let config = Config::fetch_from_dir(fake_dir.path()).unwrap();
+        assert_eq!(config.http.proxy, None);
+
+        let explicit = fake_dir.path().join("missing.toml");
+        assert!(Config::fetch_from_file(&explicit, true).is_err());
+    }
+
+    #[test]
+    fn partial_config() {
+        let fake_dir = tempdir().unwrap();
+        fs::write(
+            fake_dir.path().join(constants::CONFIG_FILE_NAME),
+            "[http]\nproxy = \"http://proxy.corp.example:8080\"\nno_proxy = [\".internal.example\"]\n\n[[profiles]]\nprofile = \"work\"\ndirectories = [\"~/work\"]\n",
+        )
+        .unwrap();
+
+        let config = Config::fetch_from_dir(fake_dir.path()).unwrap();
+        assert_eq!(
+            config.http.proxy.as_deref(),
+            Some("http://proxy.corp.example:8080")
+        );
+        assert!(config.http.bypasses_proxy("git.internal.example"));
+        assert!(config.http.bypasses_proxy("internal.example"));
+        assert!(!config.http.bypasses_proxy("app.pluralsight.com"));
+        assert_eq!(config.credentials.backend, CredentialsBackend::File);
+        assert_eq!(config.profiles[0].directories, [PathBuf::from("~/work")]);
+    }
+}
@@ -0,0 +1,387 @@
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+use tempfile::NamedTempFile;
+use toml_edit::{DocumentMut, Item, Table, TableLike, Value};
+
+use super::{Config, ConfigError};
+use crate::{constants, credentials::CredentialsGuard, ActivityInsightsError};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Bool,
+    Integer,
+    Text,
+    /// Given as a comma separated list
+    List,
+    Choice(&'static [&'static str]),
+}
+
+/// A value that can be read and written with `config get` and `config set`. Profile rules are
+/// arrays of tables and are left to editing the file.
+#[derive(Debug)]
+struct Setting {
+    key: &'static str,
+    kind: Kind,
+    /// The value used when the key isn't in the file, empty when there isn't one
+    default: fn() -> String,
+    /// What the cli does when the key isn't in the file and there's no default value
+    when_unset: Option<&'static str>,
+}
+
+const SETTINGS: &[Setting] = &[
+    Setting {
+        key: "credentials.backend",
+        kind: Kind::Choice(&["file", "encrypted"]),
+        default: || String::from("file"),
+        when_unset: None,
+    },
+    Setting {
+        key: "credentials.key_file",
+        kind: Kind::Text,
+        default: String::new,
+        when_unset: Some("the passphrase is read from ACTIVITY_INSIGHTS_PASSPHRASE"),
+    },
+    Setting {
+        key: "http.proxy",
+        kind: Kind::Text,
+        default: String::new,
+        when_unset: Some("requests are sent directly"),
+    },
+    Setting {
+        key: "http.no_proxy",
+        kind: Kind::List,
+        default: String::new,
+        when_unset: None,
+    },
+    Setting {
+        key: "http.ca_certificates",
+        kind: Kind::List,
+        default: String::new,
+        when_unset: None,
+    },
+    Setting {
+        key: "http.connect_timeout_secs",
+        kind: Kind::Integer,
+        default: || constants::CONNECT_TIMEOUT_SECS.to_string(),
+        when_unset: None,
+    },
+    Setting {
+        key: "http.request_timeout_secs",
+        kind: Kind::Integer,
+        default: || constants::REQUEST_TIMEOUT_SECS.to_string(),
+        when_unset: None,
+    },
+    Setting {
+        key: "http.download_timeout_secs",
+        kind: Kind::Integer,
+        default: || constants::DOWNLOAD_TIMEOUT_SECS.to_string(),
+        when_unset: None,
+    },
+    Setting {
+        key: "pulses.gzip",
+        kind: Kind::Bool,
+        default: String::new,
+        when_unset: Some("pulses are gzipped once the api accepts it"),
+    },
+    Setting {
+        key: "updates.auto",
+        kind: Kind::Bool,
+        default: || String::from("true"),
+        when_unset: None,
+    },
+];
+
+/// Where the effective value of a setting came from
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Default,
+    File(PathBuf),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Effective {
+    pub key: &'static str,
+    pub value: String,
+    pub source: Source,
+    /// What the cli does instead, when the setting is unset and has no default value
+    pub when_unset: Option<&'static str>,
+}
+
+impl Setting {
+    fn find(key: &str) -> Result<&'static Setting, ConfigError> {
+        SETTINGS
+            .iter()
+            .find(|setting| setting.key == key)
+            .ok_or_else(|| {
+                let keys: Vec<_> = SETTINGS.iter().map(|setting| setting.key).collect();
+                ConfigError::UnknownKey(key.to_string(), keys.join(", "))
+            })
+    }
+
+    fn parse(&self, value: &str) -> Result<Value, ConfigError> {
+        let invalid = |expected: &str| ConfigError::InvalidValue {
+            key: self.key,
+            value: value.to_string(),
+            expected: expected.to_string(),
+        };
+
+        match self.kind {
+            Kind::Bool => value
+                .parse::<bool>()
+                .map(Value::from)
+                .map_err(|_| invalid("true or false")),
+            // TOML integers are signed
+            Kind::Integer => value
+                .parse::<i64>()
+                .ok()
+                .filter(|value| *value >= 0)
+                .map(Value::from)
+                .ok_or_else(|| invalid("a whole number")),
+            Kind::Text => Ok(Value::from(value)),
+            Kind::List => Ok(Value::Array(
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .collect(),
+            )),
+            Kind::Choice(choices) if choices.contains(&value) => Ok(Value::from(value)),
+            Kind::Choice(choices) => Err(invalid(&format!("one of {}", choices.join(", ")))),
+        }
+    }
+
+    fn path(&self) -> impl Iterator<Item = &'static str> {
+        self.key.split('.')
+    }
+}
+
+/// Every setting along with where its value comes from
+pub fn list(path: &Path) -> Result<Vec<Effective>, ActivityInsightsError> {
+    let file = read_document(path)?;
+    Ok(SETTINGS
+        .iter()
+        .map(|setting| effective(setting, &file, path))
+        .collect())
+}
+
+pub fn get(path: &Path, key: &str) -> Result<Effective, ActivityInsightsError> {
+    let setting = Setting::find(key)?;
+    Ok(effective(setting, &read_document(path)?, path))
+}
+
+pub fn set(path: &Path, key: &str, value: &str) -> Result<(), ActivityInsightsError> {
+    let setting = Setting::find(key)?;
+    let value = setting.parse(value)?;
+    update(path, |file| {
+        let mut keys: Vec<_> = setting.path().collect();
+        let leaf = keys.pop().expect("setting keys aren't empty");
+        let mut table: &mut dyn TableLike = file.as_table_mut();
+        for key in keys {
+            if !table.get(key).is_some_and(Item::is_table_like) {
+                table.insert(key, Item::Table(Table::new()));
+            }
+            table = table
+                .get_mut(key)
+                .and_then(Item::as_table_like_mut)
+                .expect("replaced with a table");
+        }
+        table.insert(leaf, toml_edit::value(value));
+    })
+}
+
+/// Empty sections are removed along with the key
+pub fn unset(path: &Path, key: &str) -> Result<(), ActivityInsightsError> {
+    let setting = Setting::find(key)?;
+    update(path, |file| {
+        let keys: Vec<_> = setting.path().collect();
+        remove(file.as_table_mut(), &keys);
+    })
+}
+
+fn remove(table: &mut dyn TableLike, keys: &[&str]) {
+    match keys {
+        [] => (),
+        [leaf] => {
+            table.remove(leaf);
+        }
+        [section, rest @ ..] => {
+            if let Some(inner) = table.get_mut(section).and_then(Item::as_table_like_mut) {
+                remove(inner, rest);
+                if inner.is_empty() {
+                    table.remove(section);
+                }
+            }
+        }
+    }
+}
+
+fn effective(setting: &'static Setting, file: &DocumentMut, path: &Path) -> Effective {
+    let found = setting
+        .path()
+        .try_fold(file.as_item(), |item, key| item.get(key))
+        .and_then(Item::as_value);
+
+    match found {
+        Some(value) => Effective {
+            key: setting.key,
+            value: display(value),
+            source: Source::File(path.to_path_buf()),
+            when_unset: None,
+        },
+        None => Effective {
+            key: setting.key,
+            value: (setting.default)(),
+            source: Source::Default,
+            when_unset: setting.when_unset,
+        },
+    }
+}
+
+fn display(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.value().clone(),
+        Value::Array(items) => items.iter().map(display).collect::<Vec<_>>().join(","),
+        other => other.clone().decorated("", "").to_string(),
+    }
+}
+
+fn read_document(path: &Path) -> Result<DocumentMut, ActivityInsightsError> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(content.parse().map_err(ConfigError::from)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(DocumentMut::new()),
+        Err(e) => Err(ActivityInsightsError::IO(path.to_path_buf(), e)),
+    }
+}
+
+/// Edits go through the same lock and rename the credentials are updated with, and the edited
+/// file has to still be a valid config before it replaces the old one. Everything `edit` doesn't
+/// touch, comments included, is kept as it was.
+fn update(path: &Path, edit: impl FnOnce(&mut DocumentMut)) -> Result<(), ActivityInsightsError> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    fs::create_dir_all(dir).map_err(|e| ActivityInsightsError::IO(dir.to_path_buf(), e))?;
+
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    let _lock = CredentialsGuard::new(Path::new(&lock_path))?;
+
+    let mut file = read_document(path)?;
+    edit(&mut file);
+
+    let content = file.to_string();
+    toml::from_str::<Config>(&content).map_err(ConfigError::from)?;
+
+    let ephemeral_update_file =
+        NamedTempFile::new_in(dir).map_err(|e| ActivityInsightsError::IO(dir.to_path_buf(), e))?;
+    let ephemeral_path = ephemeral_update_file.path();
+    fs::write(ephemeral_path, content)
+        .and_then(|_| fs::rename(ephemeral_path, path))
+        .map_err(|e| ActivityInsightsError::IO(ephemeral_path.to_path_buf(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn set_get_and_unset() {
+        let fake_dir = tempdir().unwrap();
+        let path = fake_dir.path().join(constants::CONFIG_FILE_NAME);
+        fs::write(
+            &path,
+            "[[profiles]]\nprofile = \"work\"\ndirectories = [\"~/work\"]\n",
+        )
+        .unwrap();
+
+        let timeout = get(&path, "http.request_timeout_secs").unwrap();
+        assert_eq!(
+            (timeout.value.as_str(), timeout.source),
+            ("30", Source::Default)
+        );
+
+        set(&path, "http.request_timeout_secs", "60").unwrap();
+        set(&path, "http.no_proxy", ".internal.example, localhost").unwrap();
+        let timeout = get(&path, "http.request_timeout_secs").unwrap();
+        assert_eq!(
+            (timeout.value.as_str(), timeout.source),
+            ("60", Source::File(path.clone()))
+        );
+        assert_eq!(
+            get(&path, "http.no_proxy").unwrap().value,
+            ".internal.example,localhost"
+        );
+
+        let config = Config::fetch_from_file(&path, true).unwrap();
+        assert_eq!(config.http.request_timeout_secs, Some(60));
+        assert_eq!(config.profiles[0].profile, "work");
+
+        unset(&path, "http.request_timeout_secs").unwrap();
+        unset(&path, "http.no_proxy").unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "[[profiles]]\nprofile = \"work\"\ndirectories = [\"~/work\"]\n"
+        );
+    }
+
+    #[test]
+    fn keeps_comments() {
+        let fake_dir = tempdir().unwrap();
+        let path = fake_dir.path().join(constants::CONFIG_FILE_NAME);
+        let content = "# Behind the office proxy\n[http]\nproxy = \"http://proxy.corp.example:8080\" # until March\n";
+        fs::write(&path, content).unwrap();
+
+        set(&path, "http.request_timeout_secs", "60").unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}request_timeout_secs = 60\n", content)
+        );
+
+        unset(&path, "http.request_timeout_secs").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), content);
+        assert_eq!(
+            get(&path, "http.proxy").unwrap().value,
+            "http://proxy.corp.example:8080"
+        );
+    }
+
+    #[test]
+    fn unset_values_are_empty() {
+        let fake_dir = tempdir().unwrap();
+        let path = fake_dir.path().join(constants::CONFIG_FILE_NAME);
+
+        let gzip = get(&path, "pulses.gzip").unwrap();
+        assert_eq!(gzip.value, "");
+        assert_eq!(gzip.source, Source::Default);
+        assert!(gzip.when_unset.is_some());
+
+        set(&path, "pulses.gzip", "true").unwrap();
+        let gzip = get(&path, "pulses.gzip").unwrap();
+        assert_eq!((gzip.value.as_str(), gzip.when_unset), ("true", None));
+    }
+
+    #[test]
+    fn validates_keys_and_values() {
+        let fake_dir = tempdir().unwrap();
+        let path = fake_dir.path().join(constants::CONFIG_FILE_NAME);
+
+        assert!(set(&path, "http.timeout", "60").is_err());
+        assert!(set(&path, "http.request_timeout_secs", "a minute").is_err());
+        assert!(set(&path, "credentials.backend", "keychain").is_err());
+        assert!(set(&path, "pulses.gzip", "yes").is_err());
+        assert!(!path.exists());
+
+        set(&path, "pulses.gzip", "false").unwrap();
+        assert_eq!(list(&path).unwrap().len(), SETTINGS.len());
+    }
+}
@@ -1,18 +1,61 @@
+use uuid::Uuid;
+
 pub const BAD_REGISTRATION_URL: &str =  "https://app.pluralsight.com/id?redirectTo=https://app.pluralsight.com/activity-insights-beta?error=unsuccessful-registration";
 pub const BASE_BINARY_DISTRIBUTION: &str =
     "https://ps-cdn.s3-us-west-2.amazonaws.com/learner-workflow/ps-time/";
+pub const CONFIG_FILE_NAME: &str = "config.toml";
+pub const CONNECT_TIMEOUT_SECS: u64 = 10;
+pub const CORRUPT_CREDENTIALS_EXIT_CODE: i32 = 103;
+pub const CORRUPT_CREDENTIALS_MESSAGE: &str = r#"{"error":"credentials_corrupted","message":"Activity Insights credentials were corrupted, register again","command":"register"}"#;
 pub const CRED_FILE_NAME: &str = "credentials.yaml";
 pub const LOCK_FILE_NAME: &str = "credentials.yaml.lock";
 pub const CLI_VERSION_URL: &str = "https://app.pluralsight.com/wsd/api/ps-time/version";
+pub const DOCTOR_TIMEOUT_SECS: u64 = 5;
+pub const DOWNLOAD_TIMEOUT_SECS: u64 = 300;
 pub const DASHBOARD_URL: &str = "https://app.pluralsight.com/activity-insights-beta/";
+pub const ENCRYPTED_CRED_FILE_NAME: &str = "credentials.yaml.enc";
+pub const GZIP_SUPPORT_FILE: &str = "api-accepts-gzip";
+pub const GZIP_SUPPORT_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+pub const HOME_ENV_VAR: &str = "ACTIVITY_INSIGHTS_HOME";
+pub const LOCK_RETRY_BASE_MS: u64 = 10;
+pub const LOCK_RETRY_MAX_DELAY_MS: u64 = 250;
+pub const LOCK_TIMEOUT_MS: u64 = 3_000;
 pub const LOG_FILE: &str = "activity-insights.logs";
+pub const MAX_DOWNLOAD_BYTES: u64 = 100 * 1024 * 1024;
+pub const MAX_PENDING_PULSES: usize = 10_000;
+pub const MAX_PULSE_REQUEST_BYTES: usize = 512 * 1024;
+pub const MAX_PULSES_PER_REQUEST: usize = 500;
 pub const NOT_ACCEPTED_TOS_EXIT_CODE: i32 = 100;
+pub const PASSPHRASE_ENV_VAR: &str = "ACTIVITY_INSIGHTS_PASSPHRASE";
+pub const PENDING_PULSES_FILE: &str = "pending-pulses.jsonl";
+pub const PROFILE_ENV_VAR: &str = "ACTIVITY_INSIGHTS_PROFILE";
+pub const PROFILE_TOS_NOT_ACCEPTED_EXIT_CODE: i32 = 107;
 pub const PS_DIR: &str = ".pluralsight";
+pub const PULSE_ID_NAMESPACE: Uuid = Uuid::from_bytes([
+    0xab, 0x6b, 0x29, 0x88, 0xba, 0xd1, 0x40, 0x38, 0x84, 0xbc, 0x1e, 0x25, 0xd0, 0x17, 0x6b, 0xe7,
+]);
 pub const PULSE_API_URL: &str = "https://app.pluralsight.com/wsd/api/ps-time/pulse";
+pub const PULSE_RETRY_BASE_MS: u64 = 250;
+pub const PULSE_RETRY_BUDGET_MS: u64 = 8_000;
+pub const PULSE_RETRY_MAX_DELAY_MS: u64 = 2_000;
+pub const REGISTRATION_CONFIRM_URL: &str =
+    "https://app.pluralsight.com/wsd/api/ps-time/register/confirm";
+pub const REGISTRATION_TIMEOUT_SECS: u64 = 300;
 pub const REGISTRATION_URL: &str = "https://app.pluralsight.com/id?redirectTo=https://app.pluralsight.com/wsd/api/ps-time/register";
+pub const REVOKE_TOKEN_URL: &str = "https://app.pluralsight.com/wsd/api/ps-time/revoke";
+pub const REQUEST_TIMEOUT_SECS: u64 = 30;
+pub const TOKEN_ENV_VAR: &str = "ACTIVITY_INSIGHTS_TOKEN";
+pub const TOKEN_REJECTED_EXIT_CODE: i32 = 110;
+pub const TOKEN_REJECTED_MESSAGE: &str = r#"{"error":"api_token_rejected","message":"Re-register Activity Insights","command":"register"}"#;
 pub const TOS: &str = include_str!("../terms-of-service");
-pub const TOS_VERSION: usize = include!("../terms-of-service-version");
-pub const VERSION: usize = include!("../cli-version");
+pub const TOS_CHANGES: &str = include_str!("../terms-of-service-changes");
+pub const TOS_REVOKED_EXIT_CODE: i32 = 105;
+pub const TOS_REVOKED_MESSAGE: &str = r#"{"error":"tos_revoked","message":"Activity Insights is off until the terms of service are accepted again","command":"accept_tos"}"#;
+// The version files have no trailing newline since the deploy workflow names builds after them
+pub const TOS_VERSION: &str = include_str!("../terms-of-service-version");
+pub const USAGE_EXIT_CODE: i32 = 2;
+pub const VERSION: &str = include_str!("../cli-version");
+pub const XDG_DIR_NAME: &str = "activity-insights";
 
 #[cfg(unix)]
 pub const EXECUTABLE: &str = "activity-insights";
@@ -1,15 +1,38 @@
+use chrono::{DateTime, Utc};
 use fs2::FileExt;
-use log::warn;
+use log::{info, warn};
+use semver::Version;
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::BTreeMap,
+    env, fmt,
     fs::{self, File, OpenOptions},
+    io,
     path::{Path, PathBuf},
+    rc::Rc,
+    time::Duration,
 };
-use tempfile::NamedTempFile;
 use thiserror::Error;
 use uuid::Uuid;
 
-use crate::{constants, ActivityInsightsError};
+use crate::{
+    config::{Config, CredentialsBackend, CredentialsConfig},
+    constants,
+    paths::Dirs,
+    permissions, profiles,
+    retry::{Attempt, Backoff},
+    tos::TosAcceptance,
+    ActivityInsightsError,
+};
+
+mod encrypted;
+mod env_store;
+mod file;
+mod schema;
+
+use encrypted::EncryptedFileStore;
+use env_store::EnvStore;
+use file::FileStore;
 
 #[derive(Error, Debug)]
 pub enum CredentialsError {
@@ -27,83 +50,306 @@ pub enum CredentialsError {
 
     #[error("Error deserializing the credentials file: {0}")]
     DeserializationError(#[from] serde_yaml::Error),
+
+    #[error("The credentials file was corrupted and the api token couldn't be recovered. It was backed up to {0}")]
+    Corrupted(PathBuf),
+
+    #[error("{0} is not a valid api token: {1}")]
+    InvalidEnvToken(String, String),
+
+    #[error("The api token comes from {0}, update {0} to change it")]
+    EnvTokenReadOnly(&'static str),
+
+    #[error(
+        "The encrypted credentials store needs a key_file in the config or a passphrase in {0}"
+    )]
+    MissingPassphrase(&'static str),
+
+    #[error("Encrypted credentials error: {0}")]
+    Encryption(String),
+
+    #[error("The credentials were written by a newer version of the cli (schema version {0}, this version supports up to {1}). Update the cli to use them")]
+    UnsupportedSchemaVersion(u64, u64),
+
+    #[error("Invalid credentials schema version: {0}")]
+    InvalidSchemaVersion(String),
 }
 
-#[derive(Debug, Deserialize, Serialize, Default)]
-pub struct Credentials {
+/// Somewhere credentials can be kept. Stores only read and write, the locking around a
+/// read-modify-write is done by Credentials through the lock file the store points to.
+pub trait CredentialsStore: fmt::Debug {
+    /// Only called while holding either a shared or an exclusive lock on `lock_file_path`
+    fn load(&self) -> Result<Credentials, ActivityInsightsError>;
+
+    /// Called without holding the lock when `load` fails to deserialize the credentials. Stores
+    /// that can salvage something from a corrupted file take the exclusive lock to do it.
+    fn recover(&self, error: CredentialsError) -> Result<Credentials, ActivityInsightsError> {
+        Err(error.into())
+    }
+
+    /// Only called while holding the lock on `lock_file_path`
+    fn save(&self, creds: &Credentials) -> Result<(), ActivityInsightsError>;
+
+    /// Whether the api token of the default profile comes from somewhere the store can't change
+    fn api_token_is_read_only(&self) -> bool {
+        false
+    }
+
+    fn lock_file_path(&self) -> PathBuf;
+}
+
+/// The store a Credentials was loaded from
+#[derive(Debug, Clone)]
+struct Store(Rc<dyn CredentialsStore>);
+
+impl Default for Store {
+    fn default() -> Self {
+        Store(Rc::new(FileStore::new(Path::new(""))))
+    }
+}
+
+/// An account activity is tracked under. Profiles are registered and accept the TOS separately
+#[derive(Debug, Clone, Deserialize, Serialize, Default, PartialEq)]
+pub struct Profile {
     api_token: Option<Uuid>,
-    latest_accepted_tos: Option<usize>,
+    #[serde(default)]
+    tos_acceptance: Option<TosAcceptance>,
+    /// Set when the user revoked their acceptance of the TOS. Cleared by accepting them again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tos_revoked_at: Option<DateTime<Utc>>,
+    /// Set when the api responds with a 401 or 403 for api_token. Cleared by registering again
+    #[serde(default)]
+    api_token_rejected: bool,
+    /// When registration was confirmed through the loopback redirect. Registering by opening the
+    /// browser alone never learns whether it worked, so this stays unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    registered_at: Option<DateTime<Utc>>,
+    /// Tokens replaced by rotating that the api hasn't confirmed revoking yet
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    replaced_api_tokens: Vec<Uuid>,
+}
+
+/// Stands in for a named profile that hasn't been written to the file yet
+static NEW_PROFILE: Profile = Profile {
+    api_token: None,
+    tos_acceptance: None,
+    tos_revoked_at: None,
+    api_token_rejected: false,
+    registered_at: None,
+    replaced_api_tokens: Vec::new(),
+};
+
+/// The default profile is kept at the top level, where credentials files from before profiles
+/// existed have it
+#[derive(Debug, Clone, Deserialize, Serialize, Default)]
+pub struct Credentials {
+    #[serde(flatten)]
+    default_profile: Profile,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    profiles: BTreeMap<String, Profile>,
+    /// Where a corrupted credentials file was moved to when the api token couldn't be recovered
+    /// from it. Cleared by registering again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    corrupted_backup: Option<PathBuf>,
+    /// The schema version the credentials were migrated from when they were read
+    #[serde(skip)]
+    migrated_from: Option<u64>,
+    /// The profile the accessors and updates apply to, None for the default one
     #[serde(skip)]
-    location: PathBuf,
+    profile: Option<String>,
+    #[serde(skip)]
+    store: Store,
 }
 
 impl Credentials {
+    /// The credentials of the profile named in the environment, or the default profile
     pub fn fetch() -> Result<Self, ActivityInsightsError> {
-        let creds_dir = dirs::home_dir()
-            .map(|dir| dir.join(constants::PS_DIR))
-            .ok_or_else(|| {
-                ActivityInsightsError::Other(String::from("Can't find the home directory"))
-            })?;
+        Self::fetch_profile(profiles::from_env().as_deref())
+    }
+
+    /// Credentials live in the store picked in the config, the plain file unless the encrypted
+    /// store was chosen. An api token in the environment takes precedence over the stored one for
+    /// the default profile.
+    pub fn fetch_profile(profile: Option<&str>) -> Result<Self, ActivityInsightsError> {
+        if let Some(name) = profile {
+            profiles::validate(name)?;
+        }
 
-        Self::fetch_from_dir(&creds_dir)
+        let creds_dir = Dirs::fetch()?.credentials().to_path_buf();
+
+        permissions::create_private_dir(&creds_dir)
+            .map_err(|e| ActivityInsightsError::IO(creds_dir.clone(), e))?;
+
+        let config = Config::fetch()?.credentials;
+        let store: Rc<dyn CredentialsStore> = match config.backend {
+            CredentialsBackend::File => Rc::new(FileStore::new(&creds_dir)),
+            CredentialsBackend::Encrypted => {
+                let store = EncryptedFileStore::new(&creds_dir, encryption_secret(&config)?);
+                store.import_plain_file()?;
+                Rc::new(store)
+            }
+        };
+
+        let store: Rc<dyn CredentialsStore> = match env::var(constants::TOKEN_ENV_VAR) {
+            Ok(token) => {
+                let token = token.trim().parse().map_err(|e: uuid::Error| {
+                    CredentialsError::InvalidEnvToken(token.clone(), e.to_string())
+                })?;
+                Rc::new(EnvStore::new(token, store))
+            }
+            Err(_) => store,
+        };
+
+        Self::fetch_from(store, profile.map(String::from))
     }
 
-    fn fetch_from_dir(dir: &Path) -> Result<Self, ActivityInsightsError> {
-        let path = dir.join(constants::CRED_FILE_NAME);
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&path)
-            .map_err(|e| ActivityInsightsError::IO(path.to_path_buf(), e))?;
+    fn fetch_from(
+        store: Rc<dyn CredentialsStore>,
+        profile: Option<String>,
+    ) -> Result<Self, ActivityInsightsError> {
+        // The shared lock keeps a write from another process from landing halfway through the
+        // read. It has to be released before recovering, which needs the exclusive lock.
+        let loaded = {
+            let _lock = CredentialsGuard::shared(&store.lock_file_path())?;
+            store.load()
+        };
 
-        let creds: Credentials = match serde_yaml::from_reader(file) {
-            Ok(creds) => Credentials {
-                location: dir.to_path_buf(),
-                ..creds
-            },
-            Err(e) => {
-                warn!("Error deserializing yaml: {}", e);
-                Credentials {
-                    location: dir.to_path_buf(),
-                    ..Default::default()
-                }
+        let creds = match loaded {
+            Err(ActivityInsightsError::Credentials(
+                e @ CredentialsError::DeserializationError(_),
+            )) => {
+                warn!("Error deserializing credentials: {}", e);
+                store.recover(e)?
             }
+            loaded => loaded?,
+        };
+
+        let creds = match creds.migrated_from {
+            Some(version) => Self::save_migrated(store.as_ref(), version)?,
+            None => creds,
         };
+
+        Ok(Credentials {
+            profile,
+            store: Store(store),
+            ..creds
+        })
+    }
+
+    /// Writes credentials read from an older schema back in the current one. They're read again
+    /// under the lock in case another process got to it first.
+    fn save_migrated(
+        store: &dyn CredentialsStore,
+        version: u64,
+    ) -> Result<Credentials, ActivityInsightsError> {
+        let _lock = CredentialsGuard::new(&store.lock_file_path())?;
+        let mut creds = store.load()?;
+        if creds.migrated_from.take().is_some() {
+            store.save(&creds)?;
+            info!(
+                "Migrated the credentials from schema version {} to {}",
+                version,
+                schema::CURRENT
+            );
+        }
         Ok(creds)
     }
 
+    #[cfg(test)]
+    pub(crate) fn fetch_from_dir(dir: &Path) -> Result<Self, ActivityInsightsError> {
+        Self::fetch_from(Rc::new(FileStore::new(dir)), None)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn fetch_with_env_token(
+        dir: &Path,
+        token: Uuid,
+    ) -> Result<Self, ActivityInsightsError> {
+        let file_store = Rc::new(FileStore::new(dir));
+        Self::fetch_from(Rc::new(EnvStore::new(token, file_store)), None)
+    }
+
+    /// Only called while holding the exclusive lock, which also covers the read
     fn fetch_latest(&self) -> Result<Credentials, ActivityInsightsError> {
-        Self::fetch_from_dir(&self.location)
+        let creds = self.store.0.load()?;
+        Ok(Credentials {
+            profile: self.profile.clone(),
+            store: self.store.clone(),
+            ..creds
+        })
+    }
+
+    fn active(&self) -> &Profile {
+        match &self.profile {
+            Some(name) => self.profiles.get(name).unwrap_or(&NEW_PROFILE),
+            None => &self.default_profile,
+        }
     }
 
-    fn creds_file_path(&self) -> PathBuf {
-        self.location.join(constants::CRED_FILE_NAME)
+    fn active_mut(&mut self) -> &mut Profile {
+        match &self.profile {
+            Some(name) => self.profiles.entry(name.clone()).or_default(),
+            None => &mut self.default_profile,
+        }
     }
 
-    fn ephemeral_update_file(&self) -> Result<NamedTempFile, ActivityInsightsError> {
-        NamedTempFile::new_in(&self.location)
-            .map_err(|e| ActivityInsightsError::IO(self.location.to_path_buf(), e))
+    /// The selected profile, None for the default one
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
     }
 
-    fn lock_file_path(&self) -> PathBuf {
-        self.location.join(constants::LOCK_FILE_NAME)
+    pub fn profile_names(&self) -> impl Iterator<Item = &str> {
+        self.profiles.keys().map(String::as_str)
     }
 
     pub fn api_token(&self) -> &Option<Uuid> {
-        &self.api_token
+        &self.active().api_token
+    }
+
+    pub fn api_token_rejected(&self) -> bool {
+        self.active().api_token_rejected
+    }
+
+    /// The api token of the default profile can come from the environment, in which case it can't
+    /// be replaced or removed
+    pub fn api_token_is_read_only(&self) -> bool {
+        self.profile.is_none() && self.store.0.api_token_is_read_only()
     }
 
-    pub fn has_accepted_latest(&self, latest_version: usize) -> bool {
-        if let Some(val) = self.latest_accepted_tos {
-            val >= latest_version
-        } else {
-            false
+    pub fn replaced_api_tokens(&self) -> &[Uuid] {
+        &self.active().replaced_api_tokens
+    }
+
+    pub fn registered_at(&self) -> Option<DateTime<Utc>> {
+        self.active().registered_at
+    }
+
+    pub fn tos_acceptance(&self) -> Option<&TosAcceptance> {
+        self.active().tos_acceptance.as_ref()
+    }
+
+    pub fn tos_revoked_at(&self) -> Option<DateTime<Utc>> {
+        self.active().tos_revoked_at
+    }
+
+    /// Fails if the credentials were reset after the file was corrupted, which means the user has
+    /// to register again
+    pub fn check_integrity(&self) -> Result<(), CredentialsError> {
+        match &self.corrupted_backup {
+            Some(backup) => Err(CredentialsError::Corrupted(backup.clone())),
+            None => Ok(()),
+        }
+    }
+
+    pub fn has_accepted_latest(&self, latest_version: &Version) -> bool {
+        match &self.active().tos_acceptance {
+            Some(accepted) => accepted.version >= *latest_version,
+            None => false,
         }
     }
 
     pub fn lock(&mut self) -> Result<CredentialsGuard, ActivityInsightsError> {
-        CredentialsGuard::new(&self.lock_file_path())
+        CredentialsGuard::new(&self.store.0.lock_file_path())
     }
 
     /// create_api_token only adds an api token if one is not already there. This prevents the user
@@ -123,7 +369,8 @@ impl Credentials {
         }
 
         let new_token = Uuid::new_v4();
-        fresh_creds.api_token = Some(new_token);
+        fresh_creds.active_mut().api_token = Some(new_token);
+        fresh_creds.corrupted_backup = None;
 
         if let Err(e) = lock.update(&fresh_creds) {
             return Err(e);
@@ -132,18 +379,180 @@ impl Credentials {
         Ok(new_token)
     }
 
-    pub fn accept_tos(&mut self, tos_version: usize) -> Result<(), ActivityInsightsError> {
+    /// Flags the api token as rejected, unless another process has replaced it in the meantime
+    pub fn reject_api_token(&mut self) -> Result<(), ActivityInsightsError> {
+        let lock = self.lock()?;
+        let mut fresh_creds = self.fetch_latest()?;
+
+        if fresh_creds.api_token() != self.api_token() {
+            return Ok(());
+        }
+
+        fresh_creds.active_mut().api_token_rejected = true;
+        lock.update(&fresh_creds)?;
+        self.active_mut().api_token_rejected = true;
+
+        Ok(())
+    }
+
+    /// The server won't take a rejected token again, so registering again needs a new one
+    pub fn replace_rejected_api_token(&mut self) -> Result<Uuid, ActivityInsightsError> {
+        let lock = self.lock()?;
+        let mut fresh_creds = self.fetch_latest()?;
+
+        if let (Some(token), false) = (*fresh_creds.api_token(), fresh_creds.api_token_rejected()) {
+            return Ok(token);
+        }
+
+        let new_token = Uuid::new_v4();
+        let profile = fresh_creds.active_mut();
+        profile.api_token = Some(new_token);
+        profile.api_token_rejected = false;
+        profile.registered_at = None;
+        lock.update(&fresh_creds)?;
+
+        let profile = self.active_mut();
+        profile.api_token = Some(new_token);
+        profile.api_token_rejected = false;
+        profile.registered_at = None;
+        Ok(new_token)
+    }
+
+    /// Wipes the api token so the next registration creates a new one. Returns the token that was
+    /// removed, if there was one.
+    pub fn remove_api_token(&mut self) -> Result<Option<Uuid>, ActivityInsightsError> {
+        let lock = self.lock()?;
+        let mut fresh_creds = self.fetch_latest()?;
+
+        let profile = fresh_creds.active_mut();
+        let removed = profile.api_token.take();
+        profile.api_token_rejected = false;
+        profile.registered_at = None;
+        lock.update(&fresh_creds)?;
+
+        let profile = self.active_mut();
+        profile.api_token = None;
+        profile.api_token_rejected = false;
+        profile.registered_at = None;
+        Ok(removed)
+    }
+
+    /// Swaps the api token for a new one, returning the old token and the new one. The old token
+    /// is kept with the replaced tokens until it's revoked.
+    pub fn rotate_api_token(&mut self) -> Result<(Option<Uuid>, Uuid), ActivityInsightsError> {
+        let lock = self.lock()?;
+        let mut fresh_creds = self.fetch_latest()?;
+
+        let new_token = Uuid::new_v4();
+        let profile = fresh_creds.active_mut();
+        let old_token = profile.api_token.replace(new_token);
+        profile.api_token_rejected = false;
+        profile.registered_at = None;
+        profile.replaced_api_tokens.extend(old_token);
+        fresh_creds.corrupted_backup = None;
+        let replaced = fresh_creds.active().replaced_api_tokens.clone();
+        lock.update(&fresh_creds)?;
+
+        let profile = self.active_mut();
+        profile.api_token = Some(new_token);
+        profile.api_token_rejected = false;
+        profile.registered_at = None;
+        profile.replaced_api_tokens = replaced;
+        Ok((old_token, new_token))
+    }
+
+    /// Drops a replaced token once the api has revoked it
+    pub fn forget_replaced_api_token(
+        &mut self,
+        api_token: Uuid,
+    ) -> Result<(), ActivityInsightsError> {
+        let lock = self.lock()?;
+        let mut fresh_creds = self.fetch_latest()?;
+        fresh_creds
+            .active_mut()
+            .replaced_api_tokens
+            .retain(|token| *token != api_token);
+        let replaced = fresh_creds.active().replaced_api_tokens.clone();
+        lock.update(&fresh_creds)?;
+
+        self.active_mut().replaced_api_tokens = replaced;
+        Ok(())
+    }
+
+    /// Records that the api confirmed the registration of `api_token`. Nothing is recorded if the
+    /// token was replaced while the registration was going on.
+    pub fn mark_registered(
+        &mut self,
+        api_token: Uuid,
+        at: DateTime<Utc>,
+    ) -> Result<(), ActivityInsightsError> {
+        let lock = self.lock()?;
+        let mut fresh_creds = self.fetch_latest()?;
+
+        if *fresh_creds.api_token() != Some(api_token) {
+            return Ok(());
+        }
+
+        fresh_creds.active_mut().registered_at = Some(at);
+        lock.update(&fresh_creds)?;
+        self.active_mut().registered_at = Some(at);
+        Ok(())
+    }
+
+    pub fn accept_tos(&mut self, acceptance: TosAcceptance) -> Result<(), ActivityInsightsError> {
         let lock = self.lock()?;
 
         let mut fresh_creds = self.fetch_latest()?;
-        fresh_creds.latest_accepted_tos = Some(tos_version);
+        let profile = fresh_creds.active_mut();
+        profile.tos_acceptance = Some(acceptance.clone());
+        profile.tos_revoked_at = None;
 
         lock.update(&fresh_creds)?;
 
+        let profile = self.active_mut();
+        profile.tos_acceptance = Some(acceptance);
+        profile.tos_revoked_at = None;
         Ok(())
     }
+
+    /// Withdraws the acceptance of the TOS, nothing is collected for the profile until they're
+    /// accepted again
+    pub fn revoke_tos(&mut self, at: DateTime<Utc>) -> Result<(), ActivityInsightsError> {
+        let lock = self.lock()?;
+
+        let mut fresh_creds = self.fetch_latest()?;
+        let profile = fresh_creds.active_mut();
+        profile.tos_acceptance = None;
+        profile.tos_revoked_at = Some(at);
+
+        lock.update(&fresh_creds)?;
+
+        let profile = self.active_mut();
+        profile.tos_acceptance = None;
+        profile.tos_revoked_at = Some(at);
+        Ok(())
+    }
+}
+
+/// A key file takes precedence over a passphrase from the environment
+fn encryption_secret(config: &CredentialsConfig) -> Result<Vec<u8>, ActivityInsightsError> {
+    if let Some(key_file) = &config.key_file {
+        let secret =
+            fs::read(key_file).map_err(|e| ActivityInsightsError::IO(key_file.clone(), e))?;
+        return Ok(secret);
+    }
+
+    env::var(constants::PASSPHRASE_ENV_VAR)
+        .map(String::into_bytes)
+        .map_err(|_| CredentialsError::MissingPassphrase(constants::PASSPHRASE_ENV_VAR).into())
 }
 
+const LOCK_BACKOFF: Backoff = Backoff {
+    base: Duration::from_millis(constants::LOCK_RETRY_BASE_MS),
+    max_delay: Duration::from_millis(constants::LOCK_RETRY_MAX_DELAY_MS),
+    budget: Duration::from_millis(constants::LOCK_TIMEOUT_MS),
+};
+
 /// Responsible for controlling the lock on the Credentials file and updating the credentials to disk. Lock is released when it goes out of
 /// scope
 #[derive(Debug)]
@@ -152,32 +561,40 @@ pub struct CredentialsGuard {
 }
 
 impl CredentialsGuard {
+    /// Waits for other processes to finish with the credentials, giving up with the
+    /// WouldBlock error once LOCK_TIMEOUT_MS has passed
     pub fn new(path: &Path) -> Result<Self, ActivityInsightsError> {
-        let lock_file = OpenOptions::new()
+        Self::acquire(path, FileExt::try_lock_exclusive)
+    }
+
+    /// A lock for reading, which any number of processes can hold at once
+    fn shared(path: &Path) -> Result<Self, ActivityInsightsError> {
+        Self::acquire(path, FileExt::try_lock_shared)
+    }
+
+    fn acquire(
+        path: &Path,
+        try_lock: fn(&File) -> io::Result<()>,
+    ) -> Result<Self, ActivityInsightsError> {
+        let lock_file = permissions::private(&mut OpenOptions::new())
             .write(true)
             .create(true)
             .open(&path)
             .map_err(|e| ActivityInsightsError::IO(path.to_path_buf(), e))?;
 
-        lock_file
-            .try_lock_exclusive()
+        let contended = fs2::lock_contended_error().raw_os_error();
+        LOCK_BACKOFF
+            .run(|_| match try_lock(&lock_file) {
+                Ok(()) => Attempt::Done(()),
+                Err(e) if e.raw_os_error() == contended => Attempt::Retry(e, None),
+                Err(e) => Attempt::Fail(e),
+            })
             .map_err(|e| ActivityInsightsError::IO(path.to_path_buf(), e))?;
         Ok(CredentialsGuard { lock_file })
     }
 
     fn update(&self, creds: &Credentials) -> Result<(), ActivityInsightsError> {
-        let ephemeral_update_file = creds.ephemeral_update_file()?;
-        let credentials_file = creds.creds_file_path();
-        fs::write(
-            &ephemeral_update_file,
-            serde_yaml::to_vec(creds).map_err(CredentialsError::from)?,
-        )
-        .map_err(|e| ActivityInsightsError::IO(ephemeral_update_file.path().to_path_buf(), e))?;
-        fs::rename(ephemeral_update_file.path(), &credentials_file).map_err(|e| {
-            ActivityInsightsError::IO(ephemeral_update_file.path().to_path_buf(), e)
-        })?;
-
-        Ok(())
+        creds.store.0.save(creds)
     }
 }
 
@@ -195,7 +612,7 @@ mod tests {
 
         let updated_creds = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
 
-        assert_eq!(updated_creds.api_token, Some(api_token));
+        assert_eq!(updated_creds.default_profile.api_token, Some(api_token));
     }
 
     #[test]
@@ -218,6 +635,44 @@ mod tests {
         }
     }
 
+    #[test]
+    fn waits_for_lock() {
+        let fake_dir = tempdir().unwrap();
+        let lock_file_path = fake_dir.path().join(constants::LOCK_FILE_NAME);
+
+        let lock = CredentialsGuard::new(&lock_file_path).unwrap();
+        let holder = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            drop(lock);
+        });
+
+        // Both the read and the update wait for the other process instead of failing
+        let mut creds = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        let api_token = creds.create_api_token().unwrap();
+        holder.join().unwrap();
+
+        let updated_creds = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        assert_eq!(updated_creds.api_token(), &Some(api_token));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn credentials_file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let fake_dir = tempdir().unwrap();
+
+        let mut creds = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        creds.create_api_token().unwrap();
+
+        for file in &[constants::CRED_FILE_NAME, constants::LOCK_FILE_NAME] {
+            let metadata = fs::metadata(fake_dir.path().join(file)).unwrap();
+            assert_eq!(
+                metadata.permissions().mode() & 0o777,
+                permissions::PRIVATE_FILE_MODE
+            );
+        }
+    }
+
     #[test]
     fn api_token_releases_lock() {
         let fake_dir = tempdir().unwrap();
@@ -234,10 +689,249 @@ mod tests {
         let fake_dir = tempdir().unwrap();
 
         let mut creds = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
-        creds.accept_tos(100).unwrap();
+        creds
+            .accept_tos(TosAcceptance::of_version(Version::new(100, 0, 0)))
+            .unwrap();
+
+        let updated_creds = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        assert_eq!(
+            updated_creds
+                .default_profile
+                .tos_acceptance
+                .unwrap()
+                .version,
+            Version::new(100, 0, 0)
+        );
+    }
+
+    #[test]
+    fn revoke_tos() {
+        let fake_dir = tempdir().unwrap();
+
+        let mut creds = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        creds.accept_tos(TosAcceptance::bundled()).unwrap();
+
+        let accepted = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        let acceptance = accepted.tos_acceptance().unwrap();
+        assert_eq!(acceptance.cli_version, Some(crate::version::cli_version()));
+        assert!(acceptance.accepted_at.is_some() && acceptance.tos_sha256.is_some());
+
+        let revoked_at = Utc::now();
+        creds.revoke_tos(revoked_at).unwrap();
+        assert!(!creds.has_accepted_latest(&crate::version::tos_version()));
+
+        let mut revoked = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        assert_eq!(revoked.tos_acceptance(), None);
+        assert_eq!(revoked.tos_revoked_at(), Some(revoked_at));
+
+        revoked.accept_tos(TosAcceptance::bundled()).unwrap();
+        let accepted = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        assert_eq!(accepted.tos_revoked_at(), None);
+    }
+
+    #[test]
+    fn profiles_are_separate() {
+        let fake_dir = tempdir().unwrap();
+        let store: Rc<dyn CredentialsStore> = Rc::new(FileStore::new(fake_dir.path()));
+
+        let mut default_creds = Credentials::fetch_from(store.clone(), None).unwrap();
+        let default_token = default_creds.create_api_token().unwrap();
+
+        let mut work_creds = Credentials::fetch_from(store.clone(), Some("work".into())).unwrap();
+        assert_eq!(work_creds.api_token(), &None);
+        let work_token = work_creds.create_api_token().unwrap();
+        work_creds
+            .accept_tos(TosAcceptance::of_version(Version::new(1, 0, 0)))
+            .unwrap();
+
+        let work_creds = Credentials::fetch_from(store.clone(), Some("work".into())).unwrap();
+        assert_eq!(work_creds.api_token(), &Some(work_token));
+        assert!(work_creds.has_accepted_latest(&Version::new(1, 0, 0)));
+        assert_eq!(work_creds.profile_names().collect::<Vec<_>>(), ["work"]);
+
+        let default_creds = Credentials::fetch_from(store, None).unwrap();
+        assert_eq!(default_creds.api_token(), &Some(default_token));
+        assert!(!default_creds.has_accepted_latest(&Version::new(1, 0, 0)));
+    }
+
+    #[test]
+    fn reject_and_replace_api_token() {
+        let fake_dir = tempdir().unwrap();
+
+        let mut creds = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        let rejected_token = creds.create_api_token().unwrap();
+        let mut creds = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        creds.reject_api_token().unwrap();
+
+        let mut rejected_creds = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        assert!(rejected_creds.api_token_rejected());
+
+        let new_token = rejected_creds.replace_rejected_api_token().unwrap();
+        assert_ne!(new_token, rejected_token);
+
+        let updated_creds = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        assert_eq!(updated_creds.default_profile.api_token, Some(new_token));
+        assert!(!updated_creds.api_token_rejected());
+    }
+
+    #[test]
+    fn mark_registered() {
+        let fake_dir = tempdir().unwrap();
+
+        let mut creds = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        let api_token = creds.create_api_token().unwrap();
+        let registered_at = Utc::now();
+
+        creds
+            .mark_registered(Uuid::new_v4(), registered_at)
+            .unwrap();
+        assert_eq!(creds.registered_at(), None);
+
+        creds.mark_registered(api_token, registered_at).unwrap();
+        let updated_creds = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        assert_eq!(updated_creds.registered_at(), Some(registered_at));
+
+        creds.rotate_api_token().unwrap();
+        let updated_creds = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        assert_eq!(updated_creds.registered_at(), None);
+    }
+
+    #[test]
+    fn remove_and_rotate_api_token() {
+        let fake_dir = tempdir().unwrap();
+
+        let mut creds = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        let first_token = creds.create_api_token().unwrap();
+        creds
+            .accept_tos(TosAcceptance::of_version(Version::new(1, 0, 0)))
+            .unwrap();
+
+        let (old_token, new_token) = creds.rotate_api_token().unwrap();
+        assert_eq!(old_token, Some(first_token));
+        assert_ne!(new_token, first_token);
+
+        let mut updated_creds = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        assert_eq!(updated_creds.api_token(), &Some(new_token));
+        assert_eq!(updated_creds.replaced_api_tokens(), [first_token]);
+
+        updated_creds
+            .forget_replaced_api_token(first_token)
+            .unwrap();
+        assert!(updated_creds.replaced_api_tokens().is_empty());
+        let mut updated_creds = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        assert!(updated_creds.replaced_api_tokens().is_empty());
+
+        assert_eq!(updated_creds.remove_api_token().unwrap(), Some(new_token));
+        assert_eq!(updated_creds.api_token(), &None);
+
+        let updated_creds = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        assert_eq!(updated_creds.api_token(), &None);
+        assert!(updated_creds.has_accepted_latest(&Version::new(1, 0, 0)));
+    }
+
+    #[test]
+    fn migrates_file_in_place() {
+        let fake_dir = tempdir().unwrap();
+        let creds_file = fake_dir.path().join(constants::CRED_FILE_NAME);
+        let token = Uuid::new_v4();
+        fs::write(
+            &creds_file,
+            format!("api_token: {}\nlatest_accepted_tos: 1\n", token),
+        )
+        .unwrap();
+
+        let creds = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        assert_eq!(creds.api_token(), &Some(token));
+        assert!(creds.has_accepted_latest(&Version::new(1, 0, 0)));
+
+        let migrated = fs::read_to_string(&creds_file).unwrap();
+        assert!(migrated.contains(&format!("schema_version: {}", schema::CURRENT)));
+
+        let newer = "schema_version: 1000\napi_token: ~\n";
+        fs::write(&creds_file, newer).unwrap();
+        assert!(Credentials::fetch_from_dir(fake_dir.path()).is_err());
+        assert_eq!(fs::read_to_string(&creds_file).unwrap(), newer);
+    }
+
+    #[test]
+    fn recover_api_token_from_corrupted_file() {
+        let fake_dir = tempdir().unwrap();
+        let token = Uuid::new_v4();
+        fs::write(
+            fake_dir.path().join(constants::CRED_FILE_NAME),
+            format!("api_token: {}\nlatest_accepted_tos: [1.0.0\n", token),
+        )
+        .unwrap();
+
+        let creds = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        assert_eq!(creds.default_profile.api_token, Some(token));
+        assert!(creds.check_integrity().is_ok());
+
+        let backups = fs::read_dir(fake_dir.path())
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().contains(".corrupt-")
+            })
+            .count();
+        assert_eq!(backups, 1);
+    }
+
+    #[test]
+    fn recover_tos_acceptance_from_corrupted_file() {
+        let fake_dir = tempdir().unwrap();
+        fs::write(
+            fake_dir.path().join(constants::CRED_FILE_NAME),
+            "schema_version: 3\napi_token: {{\ntos_acceptance:\n  version: 1.0.0\n  cli_version: 0.5.0\nprofiles:\n  work:\n    tos_acceptance:\n      version: 2.0.0\n",
+        )
+        .unwrap();
+
+        let creds = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        assert_eq!(
+            creds.tos_acceptance(),
+            Some(&TosAcceptance::of_version(Version::new(1, 0, 0)))
+        );
+    }
+
+    #[test]
+    fn named_profile_token_isnt_recovered_as_the_default() {
+        let fake_dir = tempdir().unwrap();
+        fs::write(
+            fake_dir.path().join(constants::CRED_FILE_NAME),
+            format!(
+                "schema_version: 3\napi_t{{{{\nprofiles:\n  work:\n    api_token: {}\n",
+                Uuid::new_v4()
+            ),
+        )
+        .unwrap();
+
+        let creds = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        assert_eq!(creds.api_token(), &None);
+        assert!(creds.check_integrity().is_err());
+    }
+
+    #[test]
+    fn unrecoverable_corrupted_file() {
+        let fake_dir = tempdir().unwrap();
+        fs::write(
+            fake_dir.path().join(constants::CRED_FILE_NAME),
+            "api_token: {{{{",
+        )
+        .unwrap();
+
+        let mut creds = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        match creds.check_integrity() {
+            Err(CredentialsError::Corrupted(backup)) => assert!(backup.exists()),
+            other => panic!("Expected a corrupted error, got {:?}", other),
+        }
+
+        // Still corrupted on the next run, until the user registers again
+        let creds_again = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        assert!(creds_again.check_integrity().is_err());
 
+        creds.create_api_token().unwrap();
         let updated_creds = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
-        assert_eq!(updated_creds.latest_accepted_tos, Some(100));
+        assert!(updated_creds.check_integrity().is_ok());
     }
 
     #[test]
@@ -254,12 +948,20 @@ mod tests {
             {
                 creds.create_api_token();
             }
-            creds.accept_tos(i).unwrap();
+            creds
+                .accept_tos(TosAcceptance::of_version(Version::new(i, 0, 0)))
+                .unwrap();
         }
 
         let updated_creds = Credentials::fetch_from_dir(fake_path).unwrap();
-        let actual = (updated_creds.api_token, updated_creds.latest_accepted_tos);
-        let expected = (Some(api_token), Some(100));
+        let actual = (
+            updated_creds.default_profile.api_token,
+            updated_creds
+                .default_profile
+                .tos_acceptance
+                .map(|accepted| accepted.version),
+        );
+        let expected = (Some(api_token), Some(Version::new(100, 0, 0)));
         assert_eq!(actual, expected);
     }
 
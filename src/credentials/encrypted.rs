@@ -0,0 +1,195 @@
+use log::info;
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+use std::{
+    fs, io,
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+};
+
+use super::{
+    file::{self, FileStore},
+    schema, Credentials, CredentialsError, CredentialsGuard, CredentialsStore,
+};
+use crate::{constants, ActivityInsightsError};
+
+const MAGIC: &[u8] = b"AICREDS1";
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+/// The same yaml as the file store, encrypted with ChaCha20-Poly1305 under a key derived from a
+/// passphrase or the contents of a key file. The file is laid out as:
+/// MAGIC | salt | nonce | ciphertext and tag
+#[derive(Debug)]
+pub struct EncryptedFileStore {
+    dir: PathBuf,
+    secret: Vec<u8>,
+}
+
+impl EncryptedFileStore {
+    pub fn new(dir: &Path, secret: Vec<u8>) -> Self {
+        EncryptedFileStore {
+            dir: dir.to_path_buf(),
+            secret,
+        }
+    }
+
+    fn creds_file_path(&self) -> PathBuf {
+        self.dir.join(constants::ENCRYPTED_CRED_FILE_NAME)
+    }
+
+    /// Switching to the encrypted store moves the credentials out of the plain file, so the api
+    /// token and TOS acceptance carry over and aren't left unencrypted on disk. Nothing happens
+    /// once the encrypted file exists.
+    pub fn import_plain_file(&self) -> Result<(), ActivityInsightsError> {
+        let plain_path = self.dir.join(constants::CRED_FILE_NAME);
+        let has_plain_file = |path: &Path| fs::metadata(path).is_ok_and(|m| m.len() > 0);
+        if self.creds_file_path().exists() || !has_plain_file(&plain_path) {
+            return Ok(());
+        }
+
+        let _lock = CredentialsGuard::new(&self.lock_file_path())?;
+        // Another process may have imported it while we were waiting on the lock
+        if self.creds_file_path().exists() || !has_plain_file(&plain_path) {
+            return Ok(());
+        }
+
+        self.save(&FileStore::new(&self.dir).load()?)?;
+        fs::remove_file(&plain_path).map_err(|e| ActivityInsightsError::IO(plain_path, e))?;
+        info!("Moved the plain credentials file into the encrypted store");
+        Ok(())
+    }
+
+    fn key(&self, salt: &[u8]) -> Result<LessSafeKey, ActivityInsightsError> {
+        let mut key = [0; KEY_LEN];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+            salt,
+            &self.secret,
+            &mut key,
+        );
+        let key = UnboundKey::new(&CHACHA20_POLY1305, &key)
+            .map_err(|_| CredentialsError::Encryption(String::from("Invalid key")))?;
+        Ok(LessSafeKey::new(key))
+    }
+
+    fn decrypt(&self, content: &[u8]) -> Result<Vec<u8>, ActivityInsightsError> {
+        let header_len = MAGIC.len() + SALT_LEN + NONCE_LEN;
+        if content.len() < header_len || !content.starts_with(MAGIC) {
+            return Err(CredentialsError::Encryption(String::from(
+                "Not an encrypted credentials file",
+            ))
+            .into());
+        }
+
+        let salt = &content[MAGIC.len()..MAGIC.len() + SALT_LEN];
+        let nonce = Nonce::try_assume_unique_for_key(&content[MAGIC.len() + SALT_LEN..header_len])
+            .map_err(|_| CredentialsError::Encryption(String::from("Invalid nonce")))?;
+
+        let mut in_out = content[header_len..].to_vec();
+        let plaintext = self
+            .key(salt)?
+            .open_in_place(nonce, Aad::empty(), &mut in_out)
+            .map_err(|_| {
+                CredentialsError::Encryption(String::from(
+                    "Unable to decrypt the credentials. Is the passphrase or key file right?",
+                ))
+            })?;
+        Ok(plaintext.to_vec())
+    }
+
+    fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, ActivityInsightsError> {
+        let rng = SystemRandom::new();
+        let mut salt = [0; SALT_LEN];
+        let mut nonce = [0; NONCE_LEN];
+        rng.fill(&mut salt)
+            .and_then(|_| rng.fill(&mut nonce))
+            .map_err(|_| CredentialsError::Encryption(String::from("No randomness available")))?;
+
+        let mut in_out = plaintext.to_vec();
+        self.key(&salt)?
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut in_out,
+            )
+            .map_err(|_| CredentialsError::Encryption(String::from("Unable to encrypt")))?;
+
+        Ok([MAGIC, &salt, &nonce, &in_out].concat())
+    }
+}
+
+impl CredentialsStore for EncryptedFileStore {
+    fn load(&self) -> Result<Credentials, ActivityInsightsError> {
+        let path = self.creds_file_path();
+        let content = match fs::read(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Credentials::default()),
+            Err(e) => return Err(ActivityInsightsError::IO(path, e)),
+        };
+
+        let plaintext = self.decrypt(&content)?;
+        let plaintext = String::from_utf8_lossy(&plaintext);
+        Ok(schema::parse(&plaintext)?)
+    }
+
+    fn save(&self, creds: &Credentials) -> Result<(), ActivityInsightsError> {
+        let yaml = schema::to_vec(creds)?;
+        file::write_private(&self.dir, &self.creds_file_path(), &self.encrypt(&yaml)?)
+    }
+
+    fn lock_file_path(&self) -> PathBuf {
+        self.dir.join(constants::LOCK_FILE_NAME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use tempfile::tempdir;
+
+    #[test]
+    fn encrypted_round_trip() {
+        let fake_dir = tempdir().unwrap();
+        let store = Rc::new(EncryptedFileStore::new(
+            fake_dir.path(),
+            b"correct horse".to_vec(),
+        ));
+
+        let mut creds = Credentials::fetch_from(store.clone(), None).unwrap();
+        let api_token = creds.create_api_token().unwrap();
+
+        let stored = fs::read(store.creds_file_path()).unwrap();
+        assert!(!String::from_utf8_lossy(&stored).contains(&api_token.to_string()));
+        assert_eq!(store.load().unwrap().api_token(), &Some(api_token));
+
+        let wrong_passphrase = EncryptedFileStore::new(fake_dir.path(), b"battery".to_vec());
+        assert!(wrong_passphrase.load().is_err());
+    }
+
+    #[test]
+    fn imports_the_plain_file() {
+        let fake_dir = tempdir().unwrap();
+        let mut plain = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        let api_token = plain.create_api_token().unwrap();
+
+        let store = EncryptedFileStore::new(fake_dir.path(), b"correct horse".to_vec());
+        store.import_plain_file().unwrap();
+        assert_eq!(store.load().unwrap().api_token(), &Some(api_token));
+        assert!(!fake_dir.path().join(constants::CRED_FILE_NAME).exists());
+
+        // A plain file written after the switch doesn't replace the encrypted one
+        Credentials::fetch_from_dir(fake_dir.path())
+            .unwrap()
+            .create_api_token()
+            .unwrap();
+        store.import_plain_file().unwrap();
+        assert_eq!(store.load().unwrap().api_token(), &Some(api_token));
+    }
+}
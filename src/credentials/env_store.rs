@@ -0,0 +1,111 @@
+use std::{path::PathBuf, rc::Rc};
+use uuid::Uuid;
+
+use super::{Credentials, CredentialsError, CredentialsStore, Profile};
+use crate::{constants, ActivityInsightsError};
+
+/// Takes the api token of the default profile from an environment variable, for containers and
+/// dev VMs where there's no registration flow. Everything else, like TOS acceptance, is still kept
+/// in `inner`, which never has the token from the environment written to it. A token saved in its
+/// place would never be read, so replacing or removing the token is an error.
+#[derive(Debug)]
+pub struct EnvStore {
+    token: Uuid,
+    inner: Rc<dyn CredentialsStore>,
+}
+
+impl EnvStore {
+    pub fn new(token: Uuid, inner: Rc<dyn CredentialsStore>) -> Self {
+        EnvStore { token, inner }
+    }
+}
+
+impl CredentialsStore for EnvStore {
+    fn load(&self) -> Result<Credentials, ActivityInsightsError> {
+        let mut creds = self.inner.load()?;
+        creds.default_profile.api_token = Some(self.token);
+        Ok(creds)
+    }
+
+    fn recover(&self, error: CredentialsError) -> Result<Credentials, ActivityInsightsError> {
+        let mut creds = self.inner.recover(error)?;
+        creds.default_profile.api_token = Some(self.token);
+        Ok(creds)
+    }
+
+    fn save(&self, creds: &Credentials) -> Result<(), ActivityInsightsError> {
+        if creds.default_profile.api_token != Some(self.token) {
+            return Err(CredentialsError::EnvTokenReadOnly(constants::TOKEN_ENV_VAR).into());
+        }
+
+        let stored = self.inner.load()?;
+        self.inner.save(&Credentials {
+            default_profile: Profile {
+                api_token: stored.default_profile.api_token,
+                ..creds.default_profile.clone()
+            },
+            ..creds.clone()
+        })
+    }
+
+    fn lock_file_path(&self) -> PathBuf {
+        self.inner.lock_file_path()
+    }
+
+    fn api_token_is_read_only(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credentials::file::FileStore;
+    use crate::tos::TosAcceptance;
+    use semver::Version;
+    use tempfile::tempdir;
+
+    #[test]
+    fn token_from_env_isnt_stored() {
+        let fake_dir = tempdir().unwrap();
+        let file_store = Rc::new(FileStore::new(fake_dir.path()));
+        let env_token = Uuid::new_v4();
+
+        let mut creds =
+            Credentials::fetch_from(Rc::new(EnvStore::new(env_token, file_store.clone())), None)
+                .unwrap();
+        assert_eq!(creds.api_token(), &Some(env_token));
+
+        creds
+            .accept_tos(TosAcceptance::of_version(Version::new(1, 0, 0)))
+            .unwrap();
+
+        let stored = file_store.load().unwrap();
+        assert_eq!(stored.default_profile.api_token, None);
+        assert_eq!(
+            stored.default_profile.tos_acceptance,
+            Some(TosAcceptance::of_version(Version::new(1, 0, 0)))
+        );
+    }
+
+    #[test]
+    fn token_from_env_cant_be_replaced() {
+        let fake_dir = tempdir().unwrap();
+        let file_store = Rc::new(FileStore::new(fake_dir.path()));
+        let env_token = Uuid::new_v4();
+
+        let mut creds =
+            Credentials::fetch_from(Rc::new(EnvStore::new(env_token, file_store.clone())), None)
+                .unwrap();
+        creds.reject_api_token().unwrap();
+        assert!(creds.api_token_rejected());
+
+        match creds.replace_rejected_api_token() {
+            Err(ActivityInsightsError::Credentials(CredentialsError::EnvTokenReadOnly(_))) => (),
+            other => panic!("Expected the env token to be read only, got {:?}", other),
+        }
+        assert!(creds.rotate_api_token().is_err());
+        assert!(creds.remove_api_token().is_err());
+        assert_eq!(file_store.load().unwrap().default_profile.api_token, None);
+    }
+}
@@ -0,0 +1,154 @@
+use chrono::Utc;
+use log::warn;
+use semver::Version;
+use std::{
+    fs::{self, OpenOptions},
+    io::Read,
+    path::{Path, PathBuf},
+};
+use tempfile::NamedTempFile;
+
+use super::{schema, Credentials, CredentialsError, CredentialsGuard, CredentialsStore, Profile};
+use crate::{constants, permissions, tos::TosAcceptance, ActivityInsightsError};
+
+/// The plain yaml file in the .pluralsight directory
+#[derive(Debug)]
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(dir: &Path) -> Self {
+        FileStore {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    fn creds_file_path(&self) -> PathBuf {
+        self.dir.join(constants::CRED_FILE_NAME)
+    }
+}
+
+impl CredentialsStore for FileStore {
+    fn load(&self) -> Result<Credentials, ActivityInsightsError> {
+        let content = read_creds_file(&self.creds_file_path())?;
+        Ok(schema::parse(&content)?)
+    }
+
+    /// Moves a corrupted credentials file out of the way with a timestamp and salvages what it
+    /// can from it. Resetting to a fresh file would mean registering a new api token and losing
+    /// the account the old one was registered to, so if the token can't be salvaged the new file
+    /// records where the backup went and `check_integrity` fails until the user registers again.
+    fn recover(&self, _error: CredentialsError) -> Result<Credentials, ActivityInsightsError> {
+        let path = self.creds_file_path();
+        let _lock = CredentialsGuard::new(&self.lock_file_path())?;
+
+        // Another process may have recovered the file while we were waiting on the lock
+        let content = read_creds_file(&path)?;
+        if let Ok(creds) = schema::parse(&content) {
+            return Ok(creds);
+        }
+
+        let backup = self.dir.join(format!(
+            "{}.corrupt-{}",
+            constants::CRED_FILE_NAME,
+            Utc::now().format("%Y%m%dT%H%M%S%.3fZ")
+        ));
+        fs::rename(&path, &backup).map_err(|e| ActivityInsightsError::IO(path.clone(), e))?;
+
+        // Only the default profile is salvaged, the fields of the named profiles can't be told
+        // apart once the nesting is lost
+        let mut creds = Credentials {
+            default_profile: Profile {
+                api_token: recover_field(&content, "api_token").and_then(|t| t.parse().ok()),
+                // Files from before schema version 3 have the version at the top level
+                tos_acceptance: recover_tos_version(&content)
+                    .or_else(|| recover_field(&content, "latest_accepted_tos"))
+                    .and_then(|v| Version::parse(&v).ok())
+                    .map(TosAcceptance::of_version),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        if creds.default_profile.api_token.is_some() {
+            warn!(
+                "Recovered the api token from the corrupted credentials file, backed up to {:?}",
+                backup
+            );
+        } else {
+            warn!(
+                "Unable to recover the api token from the corrupted credentials file, backed up to {:?}",
+                backup
+            );
+            creds.corrupted_backup = Some(backup);
+        }
+
+        self.save(&creds)?;
+        Ok(creds)
+    }
+
+    fn save(&self, creds: &Credentials) -> Result<(), ActivityInsightsError> {
+        write_private(&self.dir, &self.creds_file_path(), &schema::to_vec(creds)?)
+    }
+
+    fn lock_file_path(&self) -> PathBuf {
+        self.dir.join(constants::LOCK_FILE_NAME)
+    }
+}
+
+/// Writes `contents` to a temporary file readable by the user only, then renames it over `path`
+/// so readers never see a partial write
+pub(super) fn write_private(
+    dir: &Path,
+    path: &Path,
+    contents: &[u8],
+) -> Result<(), ActivityInsightsError> {
+    let ephemeral_update_file =
+        NamedTempFile::new_in(dir).map_err(|e| ActivityInsightsError::IO(dir.to_path_buf(), e))?;
+    let ephemeral_path = ephemeral_update_file.path();
+    permissions::restrict_file(ephemeral_path)
+        .and_then(|_| fs::write(ephemeral_path, contents))
+        .and_then(|_| fs::rename(ephemeral_path, path))
+        .map_err(|e| ActivityInsightsError::IO(ephemeral_path.to_path_buf(), e))
+}
+
+/// The credentials file is created empty the first time it's read, so an empty file is a fresh
+/// one rather than a corrupted one
+fn read_creds_file(path: &Path) -> Result<String, ActivityInsightsError> {
+    let mut file = permissions::private(&mut OpenOptions::new())
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)
+        .map_err(|e| ActivityInsightsError::IO(path.to_path_buf(), e))?;
+
+    let mut content = String::new();
+    file.read_to_string(&mut content)
+        .map_err(|e| ActivityInsightsError::IO(path.to_path_buf(), e))?;
+    Ok(content)
+}
+
+/// The version is the first field of the default profile's acceptance record. Records of named
+/// profiles are indented further and never match.
+fn recover_tos_version(content: &str) -> Option<String> {
+    let version_line = content
+        .lines()
+        .skip_while(|line| *line != "tos_acceptance:")
+        .nth(1)?;
+    recover_field(version_line.strip_prefix("  ")?, "version")
+}
+
+/// Looks for an unindented `key: value` line in a file that isn't valid yaml anymore. Indented
+/// lines belong to the named profiles and never match.
+fn recover_field(content: &str, key: &str) -> Option<String> {
+    content.lines().find_map(|line| {
+        let value = line.strip_prefix(key)?.trim_start().strip_prefix(':')?;
+        let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+        if value.is_empty() {
+            None
+        } else {
+            Some(value.to_string())
+        }
+    })
+}
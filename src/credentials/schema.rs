@@ -0,0 +1,148 @@
+use serde::Serialize;
+use serde_yaml::{Mapping, Value};
+
+use super::{Credentials, CredentialsError};
+
+/// Bumped whenever the layout of the credentials changes, along with a migration from the
+/// previous version
+pub const CURRENT: u64 = 3;
+
+const KEY: &str = "schema_version";
+
+/// Files written before the schema was versioned are version 1
+const UNVERSIONED: u64 = 1;
+
+/// MIGRATIONS[i] upgrades version i + 1 to version i + 2
+const MIGRATIONS: &[fn(&mut Mapping)] = &[tos_version_to_semver, tos_acceptance_records];
+
+#[derive(Serialize)]
+struct Versioned<'a> {
+    schema_version: u64,
+    #[serde(flatten)]
+    creds: &'a Credentials,
+}
+
+/// Reads credentials of any version up to CURRENT, migrating older ones in memory. Credentials
+/// from a newer version of the cli are refused rather than read with fields missing, since they'd
+/// be written back without them.
+pub fn parse(content: &str) -> Result<Credentials, CredentialsError> {
+    if content.trim().is_empty() {
+        return Ok(Credentials::default());
+    }
+
+    let mut value: Value = serde_yaml::from_str(content)?;
+    let mapping = match value.as_mapping_mut() {
+        Some(mapping) => mapping,
+        None => return Ok(serde_yaml::from_value(value)?),
+    };
+
+    let version = match mapping.remove(&Value::from(KEY)) {
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| CredentialsError::InvalidSchemaVersion(format!("{:?}", version)))?,
+        None => UNVERSIONED,
+    };
+    if version < UNVERSIONED {
+        return Err(CredentialsError::InvalidSchemaVersion(version.to_string()));
+    }
+    if version > CURRENT {
+        return Err(CredentialsError::UnsupportedSchemaVersion(version, CURRENT));
+    }
+
+    for migration in &MIGRATIONS[(version - UNVERSIONED) as usize..] {
+        migration(mapping);
+    }
+
+    let mut creds: Credentials = serde_yaml::from_value(value)?;
+    if version < CURRENT {
+        creds.migrated_from = Some(version);
+    }
+    Ok(creds)
+}
+
+pub fn to_vec(creds: &Credentials) -> Result<Vec<u8>, CredentialsError> {
+    Ok(serde_yaml::to_vec(&Versioned {
+        schema_version: CURRENT,
+        creds,
+    })?)
+}
+
+/// Version 1 had whole numbers for TOS versions
+fn tos_version_to_semver(creds: &mut Mapping) {
+    let key = Value::from("latest_accepted_tos");
+    if let Some(Value::Number(version)) = creds.get(&key) {
+        let version = format!("{}.0.0", version);
+        creds.insert(key, Value::from(version));
+    }
+}
+
+/// Version 2 only kept the accepted TOS version, version 3 keeps a record of the acceptance for
+/// the default profile and each named one
+fn tos_acceptance_records(creds: &mut Mapping) {
+    fn migrate(profile: &mut Mapping) {
+        if let Some(version) = profile.remove(&Value::from("latest_accepted_tos")) {
+            let acceptance = if version.is_null() {
+                Value::Null
+            } else {
+                let mut record = Mapping::new();
+                record.insert(Value::from("version"), version);
+                Value::Mapping(record)
+            };
+            profile.insert(Value::from("tos_acceptance"), acceptance);
+        }
+    }
+
+    migrate(creds);
+    if let Some(Value::Mapping(profiles)) = creds.get_mut(&Value::from("profiles")) {
+        profiles
+            .iter_mut()
+            .filter_map(|(_, profile)| profile.as_mapping_mut())
+            .for_each(migrate);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tos::TosAcceptance;
+    use semver::Version;
+
+    #[test]
+    fn migrates_unversioned_credentials() {
+        let token = uuid::Uuid::new_v4();
+        let creds = parse(&format!("api_token: {}\nlatest_accepted_tos: 3\n", token)).unwrap();
+
+        assert_eq!(creds.migrated_from, Some(1));
+        assert_eq!(creds.api_token(), &Some(token));
+        assert!(creds.has_accepted_latest(&Version::new(3, 0, 0)));
+
+        let written = String::from_utf8(to_vec(&creds).unwrap()).unwrap();
+        assert!(written.starts_with("---\nschema_version: 3\n"));
+        assert!(written.contains("tos_acceptance:\n  version: 3.0.0\n"));
+        assert_eq!(parse(&written).unwrap().migrated_from, None);
+    }
+
+    #[test]
+    fn migrates_profile_tos_versions() {
+        let creds = parse(
+            "schema_version: 2\napi_token: ~\nlatest_accepted_tos: ~\nprofiles:\n  work:\n    api_token: ~\n    latest_accepted_tos: 1.0.0\n",
+        )
+        .unwrap();
+
+        assert_eq!(creds.migrated_from, Some(2));
+        assert_eq!(creds.tos_acceptance(), None);
+        let work = &creds.profiles["work"];
+        assert_eq!(
+            work.tos_acceptance,
+            Some(TosAcceptance::of_version(Version::new(1, 0, 0)))
+        );
+    }
+
+    #[test]
+    fn refuses_newer_credentials() {
+        match parse("schema_version: 4\napi_token: ~\n") {
+            Err(CredentialsError::UnsupportedSchemaVersion(4, CURRENT)) => (),
+            other => panic!("Expected the newer version to be refused, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,480 @@
+use reqwest::blocking::Client;
+use semver::Version;
+use std::{
+    collections::BTreeSet,
+    fmt,
+    fs::{self, OpenOptions},
+    io::{BufRead, BufReader},
+    path::Path,
+    thread,
+};
+
+use crate::{
+    constants, fetch_latest_version, http,
+    paths::Dirs,
+    permissions,
+    queue::PulseQueue,
+    version::{self, Update, VersionResponse},
+    ActivityInsightsError, Config, Credentials,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Pass,
+    /// Something worth knowing about that doesn't stop activity from being tracked
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Status::Pass => write!(f, "PASS"),
+            Status::Warn => write!(f, "WARN"),
+            Status::Fail => write!(f, "FAIL"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub detail: String,
+}
+
+impl Check {
+    /// Errors can span several lines, details are kept to one
+    fn new(name: impl Into<String>, status: Status, detail: impl Into<String>) -> Self {
+        Check {
+            name: name.into(),
+            status,
+            detail: detail.into().replace('\n', ": "),
+        }
+    }
+}
+
+/// Everything that has to be in place for the pulses of `profile` to be tracked. Loose permissions
+/// on private files are fixed along the way.
+pub fn run(dirs: &Dirs, profile: Option<&str>) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    match dirs::home_dir() {
+        Some(home) => checks.push(check_dir("home directory", &home)),
+        None => checks.push(Check::new(
+            "home directory",
+            Status::Fail,
+            "can't find the home directory",
+        )),
+    }
+    checks.push(check_dir("install directory", &dirs.install));
+    checks.push(check_dir("config directory", &dirs.config));
+    checks.push(check_dir("data directory", &dirs.data));
+    checks.push(check_dir("state directory", &dirs.state));
+    checks.push(check_dir("cache directory", &dirs.cache));
+    checks.push(check_permissions(dirs));
+
+    let creds = Credentials::fetch_profile(profile);
+    match &creds {
+        Ok(creds) => {
+            checks.push(check_credentials(creds));
+            checks.push(check_api_token(creds));
+            checks.push(check_tos(creds));
+        }
+        Err(e) => checks.push(Check::new("credentials", Status::Fail, e.to_string())),
+    }
+
+    checks.extend(check_network());
+    checks.push(check_log(&dirs.state.join(constants::LOG_FILE)));
+
+    // The default profile, every profile with credentials and every profile the config can pick
+    let mut profiles: BTreeSet<Option<String>> = BTreeSet::new();
+    profiles.insert(profile.map(String::from));
+    profiles.insert(None);
+    if let Ok(creds) = &creds {
+        profiles.extend(creds.profile_names().map(|name| Some(name.to_string())));
+    }
+    if let Ok(config) = Config::fetch() {
+        profiles.extend(config.profiles.into_iter().map(|rule| Some(rule.profile)));
+    }
+    for profile in &profiles {
+        checks.push(check_queue(
+            &PulseQueue::in_dir(&dirs.state, profile.as_deref()),
+            profile.as_deref(),
+        ));
+    }
+
+    checks
+}
+
+/// A directory that doesn't exist yet is created when it's first needed
+fn check_dir(name: &str, dir: &Path) -> Check {
+    if !dir.exists() {
+        return Check::new(
+            name,
+            Status::Warn,
+            format!("{} doesn't exist yet", dir.display()),
+        );
+    }
+
+    match tempfile::tempfile_in(dir) {
+        Ok(_) => Check::new(name, Status::Pass, format!("{} is writable", dir.display())),
+        Err(e) => Check::new(
+            name,
+            Status::Fail,
+            format!("{} isn't writable: {}", dir.display(), e),
+        ),
+    }
+}
+
+fn check_permissions(dirs: &Dirs) -> Check {
+    let name = "permissions";
+    let mut loose = Vec::new();
+    for dir in dirs.private() {
+        match permissions::find_loose(dir) {
+            Ok(found) => loose.extend(found),
+            Err(e) => {
+                return Check::new(
+                    name,
+                    Status::Fail,
+                    format!("unable to check {}: {}", dir.display(), e),
+                )
+            }
+        }
+    }
+
+    let mut fixed = Vec::new();
+    for entry in &loose {
+        if let Err(e) = permissions::fix(entry) {
+            return Check::new(
+                name,
+                Status::Fail,
+                format!(
+                    "unable to fix {} ({:o}, should be {:o}): {}",
+                    entry.path.display(),
+                    entry.mode,
+                    entry.expected,
+                    e
+                ),
+            );
+        }
+        fixed.push(format!(
+            "{} {:o} -> {:o}",
+            entry.path.display(),
+            entry.mode,
+            entry.expected
+        ));
+    }
+
+    if fixed.is_empty() {
+        Check::new(name, Status::Pass, "private files are only readable by you")
+    } else {
+        Check::new(name, Status::Warn, format!("fixed {}", fixed.join(", ")))
+    }
+}
+
+fn check_credentials(creds: &Credentials) -> Check {
+    match creds.check_integrity() {
+        Ok(()) => Check::new("credentials", Status::Pass, "parsed"),
+        Err(e) => Check::new("credentials", Status::Fail, e.to_string()),
+    }
+}
+
+fn check_api_token(creds: &Credentials) -> Check {
+    let name = "api token";
+    match (creds.api_token(), creds.registered_at()) {
+        (None, _) => Check::new(name, Status::Fail, "not registered, run register"),
+        (Some(_), _) if creds.api_token_rejected() => Check::new(
+            name,
+            Status::Fail,
+            "rejected by the api, run register again",
+        ),
+        (Some(_), Some(at)) => Check::new(
+            name,
+            Status::Pass,
+            format!("registered on {}", at.to_rfc2822()),
+        ),
+        (Some(_), None) => Check::new(
+            name,
+            Status::Pass,
+            "present, the registration wasn't confirmed by the api",
+        ),
+    }
+}
+
+fn check_tos(creds: &Credentials) -> Check {
+    let name = "terms of service";
+    let current = version::tos_version();
+    match (creds.tos_acceptance(), creds.tos_revoked_at()) {
+        (Some(acceptance), _) if creds.has_accepted_latest(&current) => Check::new(
+            name,
+            Status::Pass,
+            format!("accepted version {}", acceptance.version),
+        ),
+        (Some(acceptance), _) => Check::new(
+            name,
+            Status::Fail,
+            format!(
+                "accepted version {}, version {} has to be accepted",
+                acceptance.version, current
+            ),
+        ),
+        (None, Some(at)) => Check::new(
+            name,
+            Status::Warn,
+            format!("revoked on {}, nothing is tracked", at.to_rfc2822()),
+        ),
+        (None, None) => Check::new(name, Status::Fail, "not accepted, run accept_tos"),
+    }
+}
+
+/// The endpoints and the version are checked at the same time with a short timeout, so doctor
+/// doesn't hang when offline
+fn check_network() -> Vec<Check> {
+    let urls = [
+        constants::PULSE_API_URL,
+        constants::CLI_VERSION_URL,
+        constants::REGISTRATION_URL,
+        constants::BASE_BINARY_DISTRIBUTION,
+    ];
+    let client = match http::doctor_client() {
+        Ok(client) => client,
+        Err(e) => {
+            return urls
+                .iter()
+                .map(|url| format!("endpoint {}", url))
+                .chain(Some("version".to_string()))
+                .map(|name| Check::new(name, Status::Fail, e.to_string()))
+                .collect()
+        }
+    };
+
+    let client = &client;
+    thread::scope(|scope| {
+        let endpoints: Vec<_> = urls
+            .iter()
+            .map(|url| scope.spawn(move || check_endpoint(client, url)))
+            .collect();
+        let version =
+            scope.spawn(|| check_version(&version::cli_version(), fetch_latest_version(client)));
+
+        endpoints
+            .into_iter()
+            .chain(Some(version))
+            .map(|check| check.join().expect("network checks don't panic"))
+            .collect()
+    })
+}
+
+/// Any response at all means the endpoint can be reached
+fn check_endpoint(client: &Client, url: &str) -> Check {
+    let name = format!("endpoint {}", url);
+    match client.head(url).send() {
+        Ok(response) => Check::new(
+            name,
+            Status::Pass,
+            format!("reachable ({})", response.status()),
+        ),
+        Err(e) => Check::new(name, Status::Fail, format!("unreachable: {}", e)),
+    }
+}
+
+fn check_version(
+    current: &Version,
+    latest: Result<VersionResponse, ActivityInsightsError>,
+) -> Check {
+    let name = "version";
+    let latest = match latest {
+        Ok(latest) => latest,
+        Err(e) => {
+            return Check::new(
+                name,
+                Status::Warn,
+                format!(
+                    "running {}, unable to get the latest version: {}",
+                    current, e
+                ),
+            )
+        }
+    };
+
+    match latest.update_for(current) {
+        Update::UpToDate => Check::new(name, Status::Pass, format!("{} is up to date", current)),
+        Update::Recommended(latest) => Check::new(
+            name,
+            Status::Warn,
+            format!("running {}, {} is available", current, latest),
+        ),
+        Update::Required(latest) => Check::new(
+            name,
+            Status::Fail,
+            format!(
+                "running {} which is no longer supported, {} is required",
+                current, latest
+            ),
+        ),
+    }
+}
+
+/// Errors in the log are the first place to look when tracking stops
+fn check_log(path: &Path) -> Check {
+    let name = "log file";
+    let size = match fs::metadata(path) {
+        Ok(metadata) => metadata.len(),
+        Err(_) => {
+            return Check::new(
+                name,
+                Status::Warn,
+                format!("{} doesn't exist yet", path.display()),
+            )
+        }
+    };
+
+    if let Err(e) = OpenOptions::new().append(true).open(path) {
+        return Check::new(
+            name,
+            Status::Fail,
+            format!("{} isn't writable: {}", path.display(), e),
+        );
+    }
+
+    let errors = fs::File::open(path)
+        .map(|file| {
+            BufReader::new(file)
+                .lines()
+                .map_while(Result::ok)
+                .filter(|line| line.contains(" ERROR "))
+                .count()
+        })
+        .unwrap_or(0);
+
+    let detail = format!(
+        "{}, {} bytes, {} errors logged",
+        path.display(),
+        size,
+        errors
+    );
+    if errors > 0 {
+        Check::new(name, Status::Warn, detail)
+    } else {
+        Check::new(name, Status::Pass, detail)
+    }
+}
+
+fn check_queue(queue: &PulseQueue, profile: Option<&str>) -> Check {
+    let name = format!("pending pulses ({})", profile.unwrap_or("default"));
+    match queue.count() {
+        Ok(0) => Check::new(name, Status::Pass, "none"),
+        Ok(count) if count >= constants::MAX_PENDING_PULSES => Check::new(
+            name,
+            Status::Fail,
+            format!("{} waiting, the oldest are being dropped", count),
+        ),
+        Ok(count) => Check::new(name, Status::Warn, format!("{} waiting to be sent", count)),
+        Err(e) => Check::new(name, Status::Fail, e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tos::TosAcceptance;
+    use chrono::Utc;
+    use tempfile::tempdir;
+
+    #[test]
+    fn api_token_states() {
+        let fake_dir = tempdir().unwrap();
+        let mut creds = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        assert_eq!(check_api_token(&creds).status, Status::Fail);
+
+        creds.create_api_token().unwrap();
+        let mut creds = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        assert_eq!(check_api_token(&creds).status, Status::Pass);
+
+        creds.reject_api_token().unwrap();
+        let check = check_api_token(&creds);
+        assert_eq!(check.status, Status::Fail);
+        assert_eq!(check.detail, "rejected by the api, run register again");
+    }
+
+    #[test]
+    fn tos_states() {
+        let fake_dir = tempdir().unwrap();
+        let mut creds = Credentials::fetch_from_dir(fake_dir.path()).unwrap();
+        assert_eq!(check_tos(&creds).status, Status::Fail);
+
+        creds
+            .accept_tos(TosAcceptance::of_version(Version::new(0, 1, 0)))
+            .unwrap();
+        assert_eq!(check_tos(&creds).status, Status::Fail);
+
+        creds.accept_tos(TosAcceptance::bundled()).unwrap();
+        assert_eq!(check_tos(&creds).status, Status::Pass);
+
+        creds.revoke_tos(Utc::now()).unwrap();
+        assert_eq!(check_tos(&creds).status, Status::Warn);
+    }
+
+    #[test]
+    fn version_states() {
+        let latest = |json| Ok(serde_json::from_str::<VersionResponse>(json).unwrap());
+        let current = Version::new(2, 1, 0);
+
+        let check = check_version(&current, latest(r#"{"version": "2.1.0"}"#));
+        assert_eq!(check.status, Status::Pass);
+
+        let check = check_version(&current, latest(r#"{"version": "2.2.0"}"#));
+        assert_eq!(check.status, Status::Warn);
+
+        let check = check_version(
+            &current,
+            latest(r#"{"version": "3.0.0", "minimumVersion": "3.0.0"}"#),
+        );
+        assert_eq!(check.status, Status::Fail);
+
+        let offline = Err(ActivityInsightsError::Other("offline".to_string()));
+        assert_eq!(check_version(&current, offline).status, Status::Warn);
+    }
+
+    #[test]
+    fn log_file_health() {
+        let fake_dir = tempdir().unwrap();
+        let log = fake_dir.path().join(constants::LOG_FILE);
+        assert_eq!(check_log(&log).status, Status::Warn);
+
+        fs::write(
+            &log,
+            "2020-08-01T00:00:00 INFO activity_insights - Starting cli...\n",
+        )
+        .unwrap();
+        assert_eq!(check_log(&log).status, Status::Pass);
+
+        fs::write(
+            &log,
+            "2020-08-01T00:00:00 ERROR activity_insights - Exiting with code: 23\n",
+        )
+        .unwrap();
+        let check = check_log(&log);
+        assert_eq!(check.status, Status::Warn);
+        assert!(check.detail.ends_with("1 errors logged"));
+    }
+
+    #[test]
+    fn pending_pulse_backlog() {
+        let fake_dir = tempdir().unwrap();
+        let queue = PulseQueue::in_dir(fake_dir.path(), Some("work"));
+        assert_eq!(check_queue(&queue, Some("work")).status, Status::Pass);
+
+        fs::write(
+            fake_dir
+                .path()
+                .join(format!("work.{}", constants::PENDING_PULSES_FILE)),
+            "{}\n{}\n",
+        )
+        .unwrap();
+        let check = check_queue(&queue, Some("work"));
+        assert_eq!(check.status, Status::Warn);
+        assert_eq!(check.detail, "2 waiting to be sent");
+    }
+}
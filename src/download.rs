@@ -0,0 +1,199 @@
+use log::{info, warn};
+use reqwest::{header::RANGE, StatusCode};
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, IsTerminal, Read, Write},
+    path::Path,
+};
+
+use crate::{constants, http, ActivityInsightsError};
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Streams the file at `url` into `destination`. If `destination` already holds part of the file
+/// from an interrupted attempt, only the remaining bytes are requested. The partial file is left
+/// in place on failure so the next attempt can pick up where this one stopped, unless the failure
+/// means its content can't be trusted.
+pub fn download(url: &str, destination: &Path) -> Result<(), ActivityInsightsError> {
+    let existing = fs::metadata(destination).map(|m| m.len()).unwrap_or(0);
+
+    let mut request = http::download_client()?.get(url);
+    if existing > 0 {
+        info!("Resuming download of {} from byte {}", url, existing);
+        request = request.header(RANGE, format!("bytes={}-", existing));
+    }
+
+    let mut response = request
+        .send()
+        .map_err(|e| ActivityInsightsError::HTTP(url.to_string(), e))?;
+
+    let offset = match response.status() {
+        StatusCode::PARTIAL_CONTENT => existing,
+        // The server ignored the range so it's sending the whole file again
+        StatusCode::OK => 0,
+        StatusCode::RANGE_NOT_SATISFIABLE => {
+            warn!("Partial download is larger than the file, starting over");
+            discard(destination)?;
+            return Err(ActivityInsightsError::BadResponse(
+                url.to_string(),
+                StatusCode::RANGE_NOT_SATISFIABLE,
+            ));
+        }
+        other => return Err(ActivityInsightsError::BadResponse(url.to_string(), other)),
+    };
+
+    let total = response.content_length().map(|len| len + offset);
+    if let Some(total) = total {
+        if total > constants::MAX_DOWNLOAD_BYTES {
+            discard(destination)?;
+            return Err(ActivityInsightsError::DownloadTooLarge(
+                url.to_string(),
+                constants::MAX_DOWNLOAD_BYTES,
+            ));
+        }
+    }
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(offset > 0)
+        .truncate(offset == 0)
+        .open(destination)
+        .map_err(|e| ActivityInsightsError::IO(destination.to_path_buf(), e))?;
+
+    let mut progress = Progress::new(total);
+    let written = match copy_limited(
+        &mut response,
+        &mut file,
+        offset,
+        constants::MAX_DOWNLOAD_BYTES,
+        |written| progress.update(written),
+    ) {
+        Ok(written) => written,
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            drop(file);
+            discard(destination)?;
+            return Err(ActivityInsightsError::DownloadTooLarge(
+                url.to_string(),
+                constants::MAX_DOWNLOAD_BYTES,
+            ));
+        }
+        Err(e) => return Err(ActivityInsightsError::IO(destination.to_path_buf(), e)),
+    };
+    progress.finish();
+
+    match total {
+        Some(total) if written != total => Err(ActivityInsightsError::Other(format!(
+            "Download from {} ended after {} of {} bytes",
+            url, written, total
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn discard(path: &Path) -> Result<(), ActivityInsightsError> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            Err(ActivityInsightsError::IO(path.to_path_buf(), e))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Copies `reader` into `writer`, counting from `offset` bytes already written. Fails with
+/// `InvalidData` as soon as the count goes over `max`. Returns the final count.
+fn copy_limited<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    offset: u64,
+    max: u64,
+    mut on_progress: impl FnMut(u64),
+) -> io::Result<u64> {
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut written = offset;
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        written += read as u64;
+        if written > max {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "download exceeded the maximum size",
+            ));
+        }
+
+        writer.write_all(&buffer[..read])?;
+        on_progress(written);
+    }
+    writer.flush()?;
+    Ok(written)
+}
+
+/// Reports download progress on stderr, but only if a person is there to see it
+struct Progress {
+    total: Option<u64>,
+    interactive: bool,
+}
+
+impl Progress {
+    fn new(total: Option<u64>) -> Self {
+        Progress {
+            total,
+            interactive: io::stderr().is_terminal(),
+        }
+    }
+
+    fn update(&mut self, written: u64) {
+        if !self.interactive {
+            return;
+        }
+
+        match self.total {
+            Some(total) if total > 0 => {
+                eprint!("\rDownloading update: {:>3}%", written * 100 / total)
+            }
+            _ => eprint!("\rDownloading update: {} KB", written / 1024),
+        }
+    }
+
+    fn finish(&self) {
+        if self.interactive {
+            eprintln!();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn copy_limited_counts_from_offset() {
+        let mut reader = Cursor::new(vec![1; 100]);
+        let mut writer = Vec::new();
+        let mut updates = Vec::new();
+
+        let written = copy_limited(&mut reader, &mut writer, 50, 150, |n| updates.push(n)).unwrap();
+
+        assert_eq!(written, 150);
+        assert_eq!(writer.len(), 100);
+        assert_eq!(updates.last(), Some(&150));
+    }
+
+    #[test]
+    fn copy_limited_stops_at_max() {
+        let mut reader = Cursor::new(vec![1; 100]);
+        let mut writer = Vec::new();
+
+        let err = copy_limited(&mut reader, &mut writer, 0, 99, |_| {}).unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(writer.is_empty());
+    }
+}
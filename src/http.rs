@@ -0,0 +1,163 @@
+use flate2::{write::GzEncoder, Compression};
+use reqwest::{
+    blocking::{Client, ClientBuilder},
+    Certificate, Proxy, Url,
+};
+use std::{
+    fs,
+    io::{self, Write},
+    time::Duration,
+};
+
+use crate::{
+    config::{Config, ConfigError, HttpConfig},
+    constants, ActivityInsightsError,
+};
+
+/// Client for calls to the pulse and version APIs
+pub fn client() -> Result<Client, ActivityInsightsError> {
+    let config = Config::fetch()?.http;
+    let timeout = config
+        .request_timeout_secs
+        .unwrap_or(constants::REQUEST_TIMEOUT_SECS);
+    build(&config, timeout)
+}
+
+/// Client for the reachability checks of doctor, which shouldn't keep the user waiting on a host
+/// that can't be reached
+pub fn doctor_client() -> Result<Client, ActivityInsightsError> {
+    build(&Config::fetch()?.http, constants::DOCTOR_TIMEOUT_SECS)
+}
+
+/// Client for downloading new versions of the cli, which needs a lot longer than an api call
+pub fn download_client() -> Result<Client, ActivityInsightsError> {
+    let config = Config::fetch()?.http;
+    let timeout = config
+        .download_timeout_secs
+        .unwrap_or(constants::DOWNLOAD_TIMEOUT_SECS);
+    build(&config, timeout)
+}
+
+fn build(config: &HttpConfig, timeout_secs: u64) -> Result<Client, ActivityInsightsError> {
+    let builder = Client::builder()
+        .timeout(Duration::from_secs(timeout_secs))
+        .connect_timeout(Duration::from_secs(
+            config
+                .connect_timeout_secs
+                .unwrap_or(constants::CONNECT_TIMEOUT_SECS),
+        ));
+
+    let builder = with_proxy(builder, config)?;
+    let builder = with_certificates(builder, config)?;
+
+    builder
+        .build()
+        .map_err(|e| ActivityInsightsError::HTTP(String::from("Building http client"), e))
+}
+
+fn with_proxy(
+    builder: ClientBuilder,
+    config: &HttpConfig,
+) -> Result<ClientBuilder, ActivityInsightsError> {
+    let proxy = match &config.proxy {
+        Some(proxy) => proxy,
+        None => return Ok(builder),
+    };
+
+    // Parse up front so a typo in the config shows up as an error instead of a silently
+    // ignored proxy
+    let proxy_url = Url::parse(proxy)
+        .map_err(|e| ConfigError::InvalidProxy(proxy.to_string(), e.to_string()))?;
+    // Surfaces schemes reqwest can't proxy through
+    Proxy::all(proxy_url.clone())
+        .map_err(|e| ConfigError::InvalidProxy(proxy.to_string(), e.to_string()))?;
+
+    let bypass = config.clone();
+    let proxy = Proxy::custom(move |url| match url.host_str() {
+        Some(host) if bypass.bypasses_proxy(host) => None,
+        _ => Some(proxy_url.clone()),
+    });
+    Ok(builder.proxy(proxy))
+}
+
+fn with_certificates(
+    builder: ClientBuilder,
+    config: &HttpConfig,
+) -> Result<ClientBuilder, ActivityInsightsError> {
+    config
+        .ca_certificates
+        .iter()
+        .try_fold(builder, |builder, path| {
+            let pem = fs::read(path).map_err(|e| ActivityInsightsError::IO(path.clone(), e))?;
+            let pem = String::from_utf8_lossy(&pem);
+            // Without any blocks the whole file is handed over for reqwest to report the error
+            let mut blocks = certificate_blocks(&pem);
+            if blocks.is_empty() {
+                blocks.push(&pem);
+            }
+
+            blocks.into_iter().try_fold(builder, |builder, block| {
+                let certificate = Certificate::from_pem(block.as_bytes())
+                    .map_err(|e| ConfigError::InvalidCertificate(path.clone(), e))?;
+                Ok(builder.add_root_certificate(certificate))
+            })
+        })
+}
+
+/// Certificate::from_pem only reads the first certificate in a file, so bundles with several
+/// certificates are split up first
+fn certificate_blocks(pem: &str) -> Vec<&str> {
+    const END: &str = "-----END CERTIFICATE-----";
+    pem.match_indices("-----BEGIN CERTIFICATE-----")
+        .filter_map(|(start, _)| {
+            let end = start + pem[start..].find(END)? + END.len();
+            Some(&pem[start..end])
+        })
+        .collect()
+}
+
+pub fn gzip(body: &[u8]) -> Result<Vec<u8>, io::Error> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body)?;
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::{io::Read, path::PathBuf};
+
+    #[test]
+    fn certificate_bundles() {
+        let bundle = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/ca-bundle.pem");
+        let pem = fs::read_to_string(&bundle).unwrap();
+        let blocks = certificate_blocks(&pem);
+        assert_eq!(blocks.len(), 2);
+        assert!(blocks
+            .iter()
+            .all(|block| Certificate::from_pem(block.as_bytes()).is_ok()));
+
+        let config = HttpConfig {
+            ca_certificates: vec![bundle],
+            ..HttpConfig::default()
+        };
+        assert!(build(&config, constants::REQUEST_TIMEOUT_SECS).is_ok());
+
+        let config = HttpConfig {
+            ca_certificates: vec![PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml")],
+            ..HttpConfig::default()
+        };
+        assert!(build(&config, constants::REQUEST_TIMEOUT_SECS).is_err());
+    }
+
+    #[test]
+    fn gzip_round_trip() {
+        let body = br#"{"pulses":[]}"#;
+        let mut decoded = Vec::new();
+        GzDecoder::new(&gzip(body).unwrap()[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, body);
+    }
+}
@@ -1,19 +1,16 @@
+use chrono::{DateTime, Utc};
 use log::{info, warn};
-use reqwest::{
-    blocking::{self, Client},
-    StatusCode,
-};
-use serde::{Deserialize, Serialize};
+use reqwest::{StatusCode, Url};
+use serde::Serialize;
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, HashSet},
     convert::TryFrom,
-    fs,
-    io::{self, BufWriter, Write},
+    fmt, fs, io,
     path::{Path, PathBuf},
-    process::{Child, Command},
+    time::{Duration, Instant},
 };
-use tempfile::NamedTempFile;
 use thiserror::Error;
+use uuid::Uuid;
 
 use polyglot_tokenizer::{Token, Tokenizer};
 
@@ -21,12 +18,34 @@ use polyglot_tokenizer::{Token, Tokenizer};
 // static PACKAGES: phf::Set<&'static str> = ...;
 include!("./codegen/packages-set.rs");
 
+mod browser;
+mod config;
 pub mod constants;
 mod credentials;
+pub mod doctor;
+mod download;
+mod http;
+mod loopback;
+mod paths;
+pub mod permissions;
+mod profiles;
+pub mod prompt;
 mod pulses;
-
+mod queue;
+mod retry;
+mod sender;
+mod tos;
+pub mod version;
+
+pub use browser::{open_browser, show_url, Shown};
+pub use config::{Config, ConfigError, Effective, Source};
 pub use credentials::{Credentials, CredentialsError};
+pub use paths::{Dirs, Moved};
+pub use profiles::ProfileSelector;
+pub use pulses::Inspection;
 use pulses::{Pulse, PulseFromEditor};
+pub use tos::{TosAcceptance, TosChange};
+use version::{Update, VersionResponse};
 
 #[derive(Debug, Error)]
 pub enum ActivityInsightsError {
@@ -42,9 +61,36 @@ pub enum ActivityInsightsError {
     #[error("{0}")]
     Credentials(#[from] CredentialsError),
 
+    #[error("{0}")]
+    Config(#[from] ConfigError),
+
+    #[error("The api token was rejected. The user needs to register again")]
+    ApiTokenRejected,
+
+    #[error("The latest terms of service haven't been accepted for this profile")]
+    TosNotAccepted,
+
+    #[error("The terms of service were revoked for this profile")]
+    TosRevoked,
+
+    #[error("Invalid profile name {0:?}, only letters, digits, - and _ are allowed")]
+    InvalidProfile(String),
+
+    #[error("Registration wasn't completed within {0:?}")]
+    RegistrationTimedOut(Duration),
+
+    #[error("Registration failed: {0}")]
+    RegistrationFailed(String),
+
     #[error("{0}")]
     Deserialization(#[from] serde_json::Error),
 
+    #[error("Download from {0} is larger than the limit of {1} bytes")]
+    DownloadTooLarge(String, u64),
+
+    #[error("No build is published for this platform: {0}")]
+    UnsupportedPlatform(String),
+
     #[error("{0}")]
     Other(String),
 }
@@ -60,116 +106,448 @@ impl<'a> PulseRequest<'a> {
     }
 }
 
-#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
-struct VersionResponse {
-    version: usize,
+/// What happened to one of the requests a batch of pulses was split into
+#[derive(Debug)]
+pub enum ChunkOutcome {
+    Sent(StatusCode),
+    /// The api responded but didn't accept the pulses. Sending them again won't help
+    Rejected(StatusCode),
+    /// Retries ran out, the pulses were queued to send later
+    Queued(ActivityInsightsError),
+    /// Queued without trying because an earlier chunk already ran out of retries, or the time
+    /// for sending ran out
+    Skipped,
+}
+
+impl fmt::Display for ChunkOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChunkOutcome::Sent(status) => write!(f, "sent ({})", status),
+            ChunkOutcome::Rejected(status) => write!(f, "rejected ({})", status),
+            ChunkOutcome::Queued(e) => write!(f, "queued after error: {}", e),
+            ChunkOutcome::Skipped => write!(f, "queued without sending"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ChunkReport {
+    pub pulses: usize,
+    pub outcome: ChunkOutcome,
+}
+
+#[derive(Debug, Default)]
+pub struct SendReport {
+    pub chunks: Vec<ChunkReport>,
+}
+
+impl SendReport {
+    pub fn all_sent(&self) -> bool {
+        self.chunks
+            .iter()
+            .all(|chunk| matches!(chunk.outcome, ChunkOutcome::Sent(_)))
+    }
+
+    /// Pulses that are waiting in the queue for the next attempt
+    pub fn queued(&self) -> usize {
+        self.chunks
+            .iter()
+            .filter(|chunk| {
+                matches!(
+                    chunk.outcome,
+                    ChunkOutcome::Queued(_) | ChunkOutcome::Skipped
+                )
+            })
+            .map(|chunk| chunk.pulses)
+            .sum()
+    }
 }
 
 pub fn build_pulses(content: &str) -> Result<Vec<Pulse>, serde_json::error::Error> {
     let editor_pulses: Vec<PulseFromEditor> = serde_json::from_str(content)?;
-    let pulses = editor_pulses
-        .into_iter()
-        .filter_map(|event| match Pulse::try_from(event) {
-            Ok(p) => Some(p),
-            Err(e) => {
-                warn!("Couldn't convert event to a pulse: {}", e);
-                None
-            }
-        })
-        .collect();
+    let pulses = editor_pulses.into_iter().filter_map(to_pulse).collect();
+    Ok(pulses)
+}
+
+/// Builds pulses grouped by the profile they're sent under, None being the default profile
+pub fn build_profile_pulses(
+    content: &str,
+    selector: &ProfileSelector,
+) -> Result<BTreeMap<Option<String>, Vec<Pulse>>, serde_json::error::Error> {
+    let editor_pulses: Vec<PulseFromEditor> = serde_json::from_str(content)?;
+    let mut pulses: BTreeMap<Option<String>, Vec<Pulse>> = BTreeMap::new();
+    for event in editor_pulses {
+        let profile = selector.for_path(event.file_path()).map(String::from);
+        if let Some(pulse) = to_pulse(event) {
+            pulses.entry(profile).or_default().push(pulse);
+        }
+    }
     Ok(pulses)
 }
 
+/// Inspects every event in `content` on its own so one bad event doesn't hide the others
+pub fn inspect_events(content: &str) -> Result<Vec<Inspection>, serde_json::error::Error> {
+    let events: Vec<serde_json::Value> = serde_json::from_str(content)?;
+    Ok(events.into_iter().map(Inspection::of).collect())
+}
+
+/// The bodies `send_pulses` would post for `pulses`, one per chunk. Pulses waiting in the queue
+/// aren't included.
+pub fn pulse_request_bodies(pulses: &[Pulse]) -> Result<Vec<String>, serde_json::error::Error> {
+    pulses::chunk_pulses(
+        pulses,
+        constants::MAX_PULSES_PER_REQUEST,
+        constants::MAX_PULSE_REQUEST_BYTES,
+    )
+    .into_iter()
+    .map(|chunk| serde_json::to_string(&PulseRequest::new(chunk)))
+    .collect()
+}
+
+fn to_pulse(event: PulseFromEditor) -> Option<Pulse> {
+    match Pulse::try_from(event) {
+        Ok(p) => Some(p),
+        Err(e) => {
+            warn!("Couldn't convert event to a pulse: {}", e);
+            None
+        }
+    }
+}
+
+/// Sends `pulses` along with any pulses that previously failed to send, split into chunks that
+/// are each sent on their own. Chunks that still can't be delivered once retries run out are
+/// queued up on disk for the next call. Pulses and the queue belong to `profile`, None being the
+/// default profile. Every call of a run shares `deadline` from `pulse_deadline`, whatever isn't
+/// sent by then is queued.
 #[cfg(not(test))]
-pub fn send_pulses(pulses: &[Pulse]) -> Result<StatusCode, ActivityInsightsError> {
-    if pulses.is_empty() {
-        return Ok(StatusCode::from_u16(204).unwrap());
+pub fn send_pulses(
+    pulses: &[Pulse],
+    profile: Option<&str>,
+    deadline: Instant,
+) -> Result<SendReport, ActivityInsightsError> {
+    let mut creds = Credentials::fetch_profile(profile)?;
+    let queue = queue::PulseQueue::open(profile)?;
+
+    // Nothing is kept for a profile that revoked the TOS, not even in the queue
+    if creds.tos_revoked_at().is_some() {
+        queue.clear()?;
+        return Err(ActivityInsightsError::TosRevoked);
+    }
+
+    let mut batch = queue.take().unwrap_or_else(|e| {
+        warn!("Unable to read pending pulses: {}", e);
+        Vec::new()
+    });
+    batch.extend_from_slice(pulses);
+
+    if batch.is_empty() {
+        return Ok(SendReport::default());
     };
 
-    let client = Client::new();
-    let creds = Credentials::fetch()?;
-    match creds.api_token() {
-        Some(token) => {
-            let res = client
-                .post(constants::PULSE_API_URL)
-                .bearer_auth(token)
-                .json(&PulseRequest::new(pulses))
-                .send()
-                .map_err(|e| {
-                    ActivityInsightsError::HTTP(constants::PULSE_API_URL.to_string(), e)
-                })?;
-            Ok(res.status())
+    // Hold on to the pulses until the user registers again rather than sending them with a token
+    // the api has already turned down
+    if creds.api_token_rejected() {
+        queue.push(&batch)?;
+        return Err(ActivityInsightsError::ApiTokenRejected);
+    }
+
+    // Profiles picked by a directory rule may not have been through the TOS prompt or registered
+    // yet
+    if !creds.has_accepted_latest(&version::tos_version()) {
+        queue.push(&batch)?;
+        return Err(ActivityInsightsError::TosNotAccepted);
+    }
+
+    let token = match *creds.api_token() {
+        Some(token) => token,
+        None => {
+            queue.push(&batch)?;
+            return Err(ActivityInsightsError::Other(String::from(
+                "No api token was found in the config file. Can't send the request without one.",
+            )));
+        }
+    };
+
+    let mut sender = match sender::PulseSender::new(token) {
+        Ok(sender) => sender,
+        Err(e) => {
+            queue.push(&batch)?;
+            return Err(e);
         }
-        None => Err(ActivityInsightsError::Other(String::from(
-            "No api token was found in the config file. Can't send the request without one.",
-        ))),
+    };
+
+    let chunks = pulses::chunk_pulses(
+        &batch,
+        constants::MAX_PULSES_PER_REQUEST,
+        constants::MAX_PULSE_REQUEST_BYTES,
+    );
+    let mut report = SendReport::default();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let outcome = if report.queued() > 0 || Instant::now() >= deadline {
+            queue.push(chunk)?;
+            ChunkOutcome::Skipped
+        } else {
+            match sender.send(chunk, deadline) {
+                Ok(status)
+                    if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN =>
+                {
+                    warn!("Api token was rejected with status {}", status);
+                    creds.reject_api_token()?;
+                    queue.push(&chunks[i..].concat())?;
+                    return Err(ActivityInsightsError::ApiTokenRejected);
+                }
+                Ok(status) if status.is_success() => ChunkOutcome::Sent(status),
+                Ok(status) => ChunkOutcome::Rejected(status),
+                Err(e) => {
+                    queue.push(chunk)?;
+                    ChunkOutcome::Queued(e)
+                }
+            }
+        };
+
+        info!(
+            "Chunk {} of {} with {} pulses: {}",
+            i + 1,
+            chunks.len(),
+            chunk.len(),
+            outcome
+        );
+        report.chunks.push(ChunkReport {
+            pulses: chunk.len(),
+            outcome,
+        });
     }
+
+    Ok(report)
 }
 
 // Don't send the pulses for the tests
 #[cfg(test)]
-pub fn send_pulses(pulses: &[Pulse]) -> Result<StatusCode, ActivityInsightsError> {
+pub fn send_pulses(
+    pulses: &[Pulse],
+    profile: Option<&str>,
+    deadline: Instant,
+) -> Result<SendReport, ActivityInsightsError> {
     // loggging out unused variables here to avoid unused warning
     log::info!(
-        "{:?}, {} {:?}",
-        Client::new(),
+        "{:?}, {} {:?} {:?} {:?} {}",
+        http::client(),
         constants::PULSE_API_URL,
-        PulseRequest::new(pulses)
+        PulseRequest::new(pulses),
+        profile,
+        deadline,
+        sender::PulseSender::new(Uuid::nil()).is_ok()
     );
-    Ok(StatusCode::default())
+    Ok(SendReport::default())
 }
 
-#[cfg(target_os = "macos")]
-pub fn open_browser(url: &str) -> Result<Child, io::Error> {
-    Command::new("open").args(&[url]).spawn()
+/// When sending the pulses of a run has to be over, so the editor isn't kept waiting however many
+/// chunks and profiles there are
+pub fn pulse_deadline() -> Instant {
+    sender::PulseSender::deadline()
 }
 
-#[cfg(target_os = "linux")]
-pub fn open_browser(url: &str) -> Result<Child, io::Error> {
-    Command::new("xdg-open").args(&[url]).spawn()
+/// `qr_code` renders the registration url as a QR code when it has to be printed
+pub fn register(profile: Option<&str>, qr_code: bool) -> Result<(), ActivityInsightsError> {
+    let mut creds = Credentials::fetch_profile(profile)?;
+    let api_token = registration_token(&mut creds)?;
+
+    open_registration(api_token, qr_code)
 }
 
-#[cfg(target_os = "windows")]
-pub fn open_browser(url: &str) -> Result<Child, io::Error> {
-    Command::new("cmd").args(&["/C", "start", url]).spawn()
+/// Registers through a listener on 127.0.0.1 that the registration page redirects back to, so the
+/// api token never goes through the browser and the cli finds out whether registration worked.
+/// Returns when the registration was confirmed.
+pub fn register_with_loopback(
+    profile: Option<&str>,
+    qr_code: bool,
+) -> Result<DateTime<Utc>, ActivityInsightsError> {
+    let mut creds = Credentials::fetch_profile(profile)?;
+    let api_token = registration_token(&mut creds)?;
+
+    let listener = loopback::LoopbackListener::bind()?;
+    let callback_url = listener.callback_url()?;
+    let url = registration_url(&[("callback", &callback_url), ("state", listener.state())])?;
+    let shown = show_url(url.as_str(), qr_code)
+        .map_err(|e| ActivityInsightsError::IO(PathBuf::from("Opening browser..."), e))?;
+    if shown == Shown::Printed {
+        eprintln!(
+            "Registration finishes by redirecting to {} on this machine. Forward that port when registering from another one.",
+            callback_url
+        );
+    }
+
+    listener.wait(
+        Duration::from_secs(constants::REGISTRATION_TIMEOUT_SECS),
+        |code| confirm_registration(api_token, code),
+    )?;
+
+    let registered_at = Utc::now();
+    creds.mark_registered(api_token, registered_at)?;
+    Ok(registered_at)
 }
 
-pub fn register() -> Result<(), ActivityInsightsError> {
-    let mut creds = Credentials::fetch()?;
-    let api_token = match creds.api_token() {
-        Some(api_token) => *api_token,
-        None => creds.create_api_token()?,
-    };
+fn registration_token(creds: &mut Credentials) -> Result<Uuid, ActivityInsightsError> {
+    match creds.api_token() {
+        Some(_) if creds.api_token_rejected() => creds.replace_rejected_api_token(),
+        Some(api_token) => Ok(*api_token),
+        None => creds.create_api_token(),
+    }
+}
 
-    open_browser(&format!(
-        "{}?apiToken={}",
-        constants::REGISTRATION_URL,
-        api_token
-    ))
+/// REGISTRATION_URL logs the user in before redirecting to the registration endpoint in its
+/// redirectTo parameter, which is where `params` go
+fn registration_url(params: &[(&str, &str)]) -> Result<Url, ActivityInsightsError> {
+    let mut login = Url::parse(constants::REGISTRATION_URL)
+        .map_err(|e| ActivityInsightsError::Other(e.to_string()))?;
+    let (_, redirect_to) = login
+        .query_pairs()
+        .find(|(key, _)| key == "redirectTo")
+        .ok_or_else(|| {
+            ActivityInsightsError::Other(String::from("No redirectTo in REGISTRATION_URL"))
+        })?;
+
+    let mut redirect_to =
+        Url::parse(&redirect_to).map_err(|e| ActivityInsightsError::Other(e.to_string()))?;
+    redirect_to.query_pairs_mut().extend_pairs(params);
+    login
+        .query_pairs_mut()
+        .clear()
+        .append_pair("redirectTo", redirect_to.as_str());
+    Ok(login)
+}
+
+/// Hands the api token to the api along with the code the registration page redirected back with
+fn confirm_registration(api_token: Uuid, code: &str) -> Result<(), ActivityInsightsError> {
+    let url = constants::REGISTRATION_CONFIRM_URL;
+    let status = http::client()?
+        .post(url)
+        .json(&serde_json::json!({ "apiToken": api_token, "code": code }))
+        .send()
+        .map_err(|e| ActivityInsightsError::HTTP(url.to_string(), e))?
+        .status();
+
+    if status.is_success() {
+        Ok(())
+    } else {
+        Err(ActivityInsightsError::BadResponse(url.to_string(), status))
+    }
+}
+
+fn open_registration(api_token: Uuid, qr_code: bool) -> Result<(), ActivityInsightsError> {
+    show_url(
+        &format!("{}?apiToken={}", constants::REGISTRATION_URL, api_token),
+        qr_code,
+    )
     .map_err(|e| ActivityInsightsError::IO(PathBuf::from("Opening browser..."), e))?;
     Ok(())
 }
 
-pub fn maybe_update() -> Result<(), ActivityInsightsError> {
-    let latest = get_latest_version()?;
-    if latest > constants::VERSION {
-        let update_location = dirs::home_dir()
-            .map(|dir| dir.join(constants::PS_DIR))
-            .ok_or_else(|| {
-                ActivityInsightsError::Other(String::from("Error getting the home directory"))
-            })?;
-
-        update_cli(&update_location, latest)?;
+/// Revokes the api token with the api, then wipes it along with any pulses still waiting to be
+/// sent with it. The token is kept if the api can't be reached so unregistering can be retried.
+/// A token from the environment is neither revoked nor removed, only the pulses are dropped.
+pub fn unregister(profile: Option<&str>) -> Result<(), ActivityInsightsError> {
+    let mut creds = Credentials::fetch_profile(profile)?;
+    unregister_from(&mut creds, &queue::PulseQueue::open(profile)?)
+}
+
+fn unregister_from(
+    creds: &mut Credentials,
+    queue: &queue::PulseQueue,
+) -> Result<(), ActivityInsightsError> {
+    revoke_replaced_api_tokens(creds)?;
+    if !creds.api_token_is_read_only() {
+        if let Some(api_token) = creds.api_token() {
+            revoke_api_token(*api_token)?;
+        }
+        creds.remove_api_token()?;
+    }
+    queue.clear()
+}
+
+/// Stops collection for `profile` right away and drops the pulses still waiting to be sent for it
+pub fn revoke_tos(profile: Option<&str>) -> Result<(), ActivityInsightsError> {
+    let mut creds = Credentials::fetch_profile(profile)?;
+    creds.revoke_tos(Utc::now())?;
+    queue::PulseQueue::open(profile)?.clear()
+}
+
+/// Registers a new api token in place of the current one and revokes the old one, even when the
+/// registration page couldn't be opened. A failure to revoke it doesn't undo the rotation, the old
+/// token is kept in the credentials so the next rotate-token or unregister tries again.
+pub fn rotate_api_token(profile: Option<&str>, qr_code: bool) -> Result<(), ActivityInsightsError> {
+    let mut creds = Credentials::fetch_profile(profile)?;
+    let (_, new_token) = creds.rotate_api_token()?;
+
+    let registration = open_registration(new_token, qr_code);
+    revoke_replaced_api_tokens(&mut creds)?;
+    registration
+}
+
+fn revoke_replaced_api_tokens(creds: &mut Credentials) -> Result<(), ActivityInsightsError> {
+    for api_token in creds.replaced_api_tokens().to_vec() {
+        revoke_api_token(api_token).map_err(|e| {
+            ActivityInsightsError::Other(format!(
+                "The old api token may still be valid, run rotate-token or unregister to try revoking it again: {}",
+                e
+            ))
+        })?;
+        creds.forget_replaced_api_token(api_token)?;
     }
     Ok(())
 }
 
-pub fn get_latest_version() -> Result<usize, ActivityInsightsError> {
-    let resp = blocking::get(constants::CLI_VERSION_URL)
+/// A token the api doesn't know about or has already turned down counts as revoked
+fn revoke_api_token(api_token: Uuid) -> Result<(), ActivityInsightsError> {
+    let url = constants::REVOKE_TOKEN_URL;
+    let status = http::client()?
+        .post(url)
+        .bearer_auth(api_token)
+        .send()
+        .map_err(|e| ActivityInsightsError::HTTP(url.to_string(), e))?
+        .status();
+
+    match status {
+        _ if status.is_success() => Ok(()),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::NOT_FOUND => Ok(()),
+        _ => Err(ActivityInsightsError::BadResponse(url.to_string(), status)),
+    }
+}
+
+pub fn maybe_update() -> Result<(), ActivityInsightsError> {
+    let current = version::cli_version();
+    let response = get_latest_version()?;
+    match response.update_for(&current) {
+        Update::UpToDate => return Ok(()),
+        Update::Recommended(latest) if Config::fetch()?.updates.auto == Some(false) => {
+            info!("Version {} is available, automatic updates are off", latest);
+            return Ok(());
+        }
+        Update::Recommended(_) => (),
+        Update::Required(latest) => warn!(
+            "Version {} is no longer supported, updating to {}",
+            current, latest
+        ),
+    };
+
+    let dirs = Dirs::fetch()?;
+    update_cli(&dirs.install, &dirs.cache, response.artifact())
+}
+
+pub fn get_latest_version() -> Result<VersionResponse, ActivityInsightsError> {
+    fetch_latest_version(&http::client()?)
+}
+
+pub(crate) fn fetch_latest_version(
+    client: &reqwest::blocking::Client,
+) -> Result<VersionResponse, ActivityInsightsError> {
+    let resp = client
+        .get(constants::CLI_VERSION_URL)
+        .send()
         .map_err(|e| ActivityInsightsError::HTTP(constants::CLI_VERSION_URL.to_string(), e))?;
     let resp: VersionResponse = serde_json::from_reader(resp)?;
 
-    Ok(resp.version)
+    Ok(resp)
 }
 
 pub fn get_libraries(content: &str) -> HashSet<&'static str> {
@@ -182,34 +560,23 @@ pub fn get_libraries(content: &str) -> HashSet<&'static str> {
         .collect()
 }
 
-pub fn update_cli(path: &Path, version: usize) -> Result<(), ActivityInsightsError> {
-    info!("Updating cli to version {}...", version);
+/// Installs the build published as `artifact` in `path`, downloading it to `download_dir` first
+pub fn update_cli(
+    path: &Path,
+    download_dir: &Path,
+    artifact: &str,
+) -> Result<(), ActivityInsightsError> {
+    info!("Updating cli to version {}...", artifact);
 
-    let download = {
-        let download_url = get_download_url(version);
+    let download_url = get_download_url(artifact)?;
 
-        let response = blocking::get(&download_url)
-            .map_err(|e| ActivityInsightsError::HTTP(download_url.to_string(), e))?;
-
-        match response.status() {
-            StatusCode::OK => match response.bytes() {
-                Ok(bytes) => bytes,
-                Err(e) => return Err(ActivityInsightsError::HTTP(download_url, e)),
-            },
-            other => return Err(ActivityInsightsError::BadResponse(download_url, other)),
-        }
-    };
-
-    let ephemeral_update_file = NamedTempFile::new_in(path)
-        .map_err(|e| ActivityInsightsError::IO(PathBuf::from("temp-file"), e))?;
-
-    let mut writer = BufWriter::new(&ephemeral_update_file);
-    if let Err(e) = writer.write(&download) {
-        return Err(ActivityInsightsError::IO(
-            ephemeral_update_file.path().to_path_buf(),
-            e,
-        ));
-    }
+    // The partial download is named after the version so an interrupted download is only ever
+    // resumed with bytes from the same build
+    fs::create_dir_all(download_dir)
+        .map_err(|e| ActivityInsightsError::IO(download_dir.to_path_buf(), e))?;
+    let partial_download_path = download_dir.join(format!("activity-insights-{}.part", artifact));
+    remove_stale_downloads(download_dir, &partial_download_path);
+    download::download(&download_url, &partial_download_path)?;
 
     let permanent_executable_path = path.join(constants::EXECUTABLE);
 
@@ -223,7 +590,7 @@ pub fn update_cli(path: &Path, version: usize) -> Result<(), ActivityInsightsErr
         ));
     }
 
-    if let Err(e) = fs::rename(&ephemeral_update_file, &permanent_executable_path) {
+    if let Err(e) = paths::move_file(&partial_download_path, &permanent_executable_path) {
         return Err(ActivityInsightsError::IO(permanent_executable_path, e));
     }
 
@@ -244,6 +611,26 @@ pub fn update_cli(path: &Path, version: usize) -> Result<(), ActivityInsightsErr
     Ok(())
 }
 
+/// Partial downloads of versions we're no longer trying to install are never going to be resumed
+fn remove_stale_downloads(dir: &Path, current: &Path) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Unable to look for stale downloads in {:?}: {}", dir, e);
+            return;
+        }
+    };
+
+    entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path != current && path.extension() == Some("part".as_ref()))
+        .for_each(|path| {
+            if let Err(e) = fs::remove_file(&path) {
+                warn!("Unable to remove stale download {:?}: {}", path, e);
+            }
+        });
+}
+
 #[cfg(unix)]
 fn give_executable_permissions(path: &Path) -> Result<(), io::Error> {
     use std::os::unix::fs::PermissionsExt;
@@ -253,31 +640,62 @@ fn give_executable_permissions(path: &Path) -> Result<(), io::Error> {
     Ok(())
 }
 
-#[cfg(target_os = "linux")]
-fn get_download_url(version: usize) -> String {
-    format!(
-        "{}linux/activity-insights-{}",
-        constants::BASE_BINARY_DISTRIBUTION,
-        version
-    )
-}
-
-#[cfg(target_os = "macos")]
-fn get_download_url(version: usize) -> String {
-    format!(
-        "{}mac/activity-insights-{}",
-        constants::BASE_BINARY_DISTRIBUTION,
-        version
-    )
+/// The directory on the distribution server holding the builds for the platform this binary was
+/// compiled for, one per target in the deploy workflow. The x86_64 gnu builds predate the other
+/// targets which is why they don't carry an architecture suffix. Returns None if we don't publish
+/// a build for this platform.
+fn platform_dir() -> Option<&'static str> {
+    if cfg!(all(
+        target_os = "linux",
+        target_arch = "x86_64",
+        target_env = "gnu"
+    )) {
+        Some("linux")
+    } else if cfg!(all(
+        target_os = "linux",
+        target_arch = "x86_64",
+        target_env = "musl"
+    )) {
+        Some("linux-musl")
+    } else if cfg!(all(
+        target_os = "linux",
+        target_arch = "aarch64",
+        target_env = "gnu"
+    )) {
+        Some("linux-aarch64")
+    } else if cfg!(all(
+        target_os = "linux",
+        target_arch = "aarch64",
+        target_env = "musl"
+    )) {
+        Some("linux-aarch64-musl")
+    } else if cfg!(all(target_os = "macos", target_arch = "x86_64")) {
+        Some("mac")
+    } else if cfg!(all(target_os = "macos", target_arch = "aarch64")) {
+        Some("mac-aarch64")
+    } else if cfg!(all(target_os = "windows", target_arch = "x86_64")) {
+        Some("windows")
+    } else {
+        None
+    }
 }
 
-#[cfg(target_os = "windows")]
-fn get_download_url(version: usize) -> String {
-    format!(
-        "{}windows/activity-insights-{}.exe",
+fn get_download_url(artifact: &str) -> Result<String, ActivityInsightsError> {
+    let platform = platform_dir().ok_or_else(|| {
+        ActivityInsightsError::UnsupportedPlatform(format!(
+            "{}-{}",
+            std::env::consts::ARCH,
+            std::env::consts::OS
+        ))
+    })?;
+
+    Ok(format!(
+        "{}{}/activity-insights-{}{}",
         constants::BASE_BINARY_DISTRIBUTION,
-        version
-    )
+        platform,
+        artifact,
+        std::env::consts::EXE_SUFFIX
+    ))
 }
 
 #[cfg(test)]
@@ -294,13 +712,44 @@ mod tests {
     use super::*;
     use std::process::Command;
 
-    const FAKE_VERSION: usize = 2;
+    // A build from before semver, published under its integer version
+    const FAKE_VERSION: &str = "2";
+
+    #[test]
+    fn unregister_leaves_the_env_token() {
+        let fake_dir = tempfile::tempdir().unwrap();
+        let env_token = Uuid::new_v4();
+        let mut creds = Credentials::fetch_with_env_token(fake_dir.path(), env_token).unwrap();
+        let queue = queue::PulseQueue::in_dir(fake_dir.path(), None);
+        fs::write(fake_dir.path().join(constants::PENDING_PULSES_FILE), "{}\n").unwrap();
+
+        unregister_from(&mut creds, &queue).unwrap();
+        assert_eq!(queue.count().unwrap(), 0);
+        assert_eq!(creds.api_token(), &Some(env_token));
+    }
+
+    #[test]
+    fn registration_url_params() {
+        let url = registration_url(&[
+            ("callback", "http://127.0.0.1:4000/callback"),
+            ("state", "abc"),
+        ])
+        .unwrap();
+
+        let (key, redirect_to) = url.query_pairs().next().unwrap();
+        assert_eq!(url.query_pairs().count(), 1);
+        assert_eq!(key, "redirectTo");
+        assert_eq!(
+            redirect_to,
+            "https://app.pluralsight.com/wsd/api/ps-time/register?callback=http%3A%2F%2F127.0.0.1%3A4000%2Fcallback&state=abc"
+        );
+    }
 
     #[cfg(unix)]
     #[test]
     fn updating() {
         let fake_dir = tempfile::tempdir().unwrap();
-        update_cli(fake_dir.path(), FAKE_VERSION).unwrap();
+        update_cli(fake_dir.path(), fake_dir.path(), FAKE_VERSION).unwrap();
 
         let entries: Vec<_> = fs::read_dir(fake_dir.path())
             .unwrap()
@@ -339,7 +788,7 @@ mod tests {
     fn updating() {
         let fake_dir = tempfile::tempdir().unwrap();
         fs::File::create(fake_dir.path().join("activity-insights.exe")).unwrap();
-        update_cli(fake_dir.path(), FAKE_VERSION).unwrap();
+        update_cli(fake_dir.path(), fake_dir.path(), FAKE_VERSION).unwrap();
 
         let mut entries: Vec<_> = fs::read_dir(fake_dir.path())
             .unwrap()
@@ -368,10 +817,21 @@ mod tests {
         assert!(exit_code.success());
     }
 
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", target_env = "gnu"))]
+    #[test]
+    fn download_url() {
+        let expected = format!(
+            "{}linux/activity-insights-{}",
+            constants::BASE_BINARY_DISTRIBUTION,
+            FAKE_VERSION
+        );
+        assert_eq!(get_download_url(FAKE_VERSION).unwrap(), expected);
+    }
+
     #[test]
     fn get_latest() {
-        let very_old_version = 0;
+        let very_old_version = semver::Version::new(0, 0, 0);
         let latest = get_latest_version().unwrap();
-        assert!(latest > very_old_version)
+        assert!(*latest.latest() > very_old_version)
     }
 }
@@ -0,0 +1,212 @@
+use log::{info, warn};
+use reqwest::Url;
+use std::{
+    io::{self, Read, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+use crate::{constants, ActivityInsightsError};
+
+const CALLBACK_PATH: &str = "/callback";
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A short lived http listener on 127.0.0.1 that the registration page redirects back to once the
+/// user has logged in. The redirect carries the `state` it was given, so redirects meant for
+/// another attempt are ignored, and a `code` the api token is confirmed with.
+#[derive(Debug)]
+pub struct LoopbackListener {
+    listener: TcpListener,
+    state: String,
+}
+
+impl LoopbackListener {
+    pub fn bind() -> Result<Self, ActivityInsightsError> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+            .map_err(|e| ActivityInsightsError::IO("Loopback listener".into(), e))?;
+
+        Ok(LoopbackListener {
+            listener,
+            state: Uuid::new_v4().to_string(),
+        })
+    }
+
+    pub fn callback_url(&self) -> Result<String, ActivityInsightsError> {
+        let addr = self
+            .listener
+            .local_addr()
+            .map_err(|e| ActivityInsightsError::IO("Loopback listener".into(), e))?;
+        Ok(format!("http://{}{}", addr, CALLBACK_PATH))
+    }
+
+    pub fn state(&self) -> &str {
+        &self.state
+    }
+
+    /// Waits for the registration page to redirect back, then hands the code to `confirm`. The
+    /// browser is sent on to the dashboard or the registration error page depending on how that
+    /// goes.
+    pub fn wait<T>(
+        &self,
+        timeout: Duration,
+        mut confirm: impl FnMut(&str) -> Result<T, ActivityInsightsError>,
+    ) -> Result<T, ActivityInsightsError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if Instant::now() >= deadline {
+                        return Err(ActivityInsightsError::RegistrationTimedOut(timeout));
+                    }
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+                Err(e) => return Err(ActivityInsightsError::IO("Loopback listener".into(), e)),
+            };
+
+            if let Some(result) = self.handle(stream, &mut confirm) {
+                return result;
+            }
+        }
+    }
+
+    /// None when the request wasn't the redirect for this registration
+    fn handle<T>(
+        &self,
+        mut stream: TcpStream,
+        confirm: &mut impl FnMut(&str) -> Result<T, ActivityInsightsError>,
+    ) -> Option<Result<T, ActivityInsightsError>> {
+        let target = match read_request_target(&mut stream) {
+            Ok(target) => target,
+            Err(e) => {
+                warn!("Unable to read a request to the loopback listener: {}", e);
+                return None;
+            }
+        };
+
+        let url = Url::parse(&format!("http://127.0.0.1{}", target)).ok()?;
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+        };
+
+        if url.path() != CALLBACK_PATH || param("state").as_deref() != Some(self.state.as_str()) {
+            respond(&mut stream, "404 Not Found", None);
+            return None;
+        }
+
+        let result = match (param("code"), param("error")) {
+            (Some(code), None) => confirm(&code),
+            (_, error) => Err(ActivityInsightsError::RegistrationFailed(
+                error.unwrap_or_else(|| String::from("No code in the registration redirect")),
+            )),
+        };
+
+        let next = match &result {
+            Ok(_) => constants::DASHBOARD_URL,
+            Err(_) => constants::BAD_REGISTRATION_URL,
+        };
+        respond(&mut stream, "302 Found", Some(next));
+        info!(
+            "Registration redirect handled, sent the browser to {}",
+            next
+        );
+        Some(result)
+    }
+}
+
+/// Reads up to the end of the headers and returns the target from the request line
+fn read_request_target(stream: &mut TcpStream) -> io::Result<String> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buffer)?;
+        if read == 0 || request.len() > MAX_REQUEST_BYTES {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+    match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some(target)) => Ok(target.to_string()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Not a GET request",
+        )),
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, location: Option<&str>) {
+    let location = location
+        .map(|location| format!("Location: {}\r\n", location))
+        .unwrap_or_default();
+    let response = format!(
+        "HTTP/1.1 {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+        status, location
+    );
+    if let Err(e) = stream.write_all(response.as_bytes()) {
+        warn!("Unable to respond to the registration redirect: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(url: &str) -> String {
+        let url = Url::parse(url).unwrap();
+        let mut stream = TcpStream::connect(("127.0.0.1", url.port().unwrap())).unwrap();
+        let target = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+        write!(stream, "GET {} HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n", target).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn waits_for_the_redirect() {
+        let listener = LoopbackListener::bind().unwrap();
+        let callback = listener.callback_url().unwrap();
+        let state = listener.state().to_string();
+
+        let browser = thread::spawn(move || {
+            let stale = get(&format!("{}?state=old&code=stale", callback));
+            let redirect = get(&format!("{}?state={}&code=abc", callback, state));
+            (stale, redirect)
+        });
+
+        let code = listener
+            .wait(Duration::from_secs(10), |code| Ok(code.to_string()))
+            .unwrap();
+        assert_eq!(code, "abc");
+
+        let (stale, redirect) = browser.join().unwrap();
+        assert!(stale.starts_with("HTTP/1.1 404"));
+        assert!(redirect.starts_with("HTTP/1.1 302"));
+        assert!(redirect.contains(constants::DASHBOARD_URL));
+    }
+
+    #[test]
+    fn times_out() {
+        let listener = LoopbackListener::bind().unwrap();
+        match listener.wait(Duration::from_millis(10), |_| Ok(())) {
+            Err(ActivityInsightsError::RegistrationTimedOut(_)) => (),
+            other => panic!("Expected a timeout, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,337 @@
+use log::warn;
+use std::{
+    env,
+    ffi::OsString,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{constants, credentials::CredentialsGuard, permissions, ActivityInsightsError};
+
+/// Where the cli keeps its files. On linux these follow the XDG base directory spec, everywhere
+/// else, or with ACTIVITY_INSIGHTS_HOME set, they're all the same directory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dirs {
+    /// config.toml
+    pub config: PathBuf,
+    /// Credentials
+    pub data: PathBuf,
+    /// Logs and pulses waiting to be sent
+    pub state: PathBuf,
+    /// Partial downloads of new versions
+    pub cache: PathBuf,
+    /// The executable editors run, which updates are installed over. Editors install it in
+    /// ~/.pluralsight so it stays there.
+    pub install: PathBuf,
+}
+
+/// A file moved out of ~/.pluralsight into the XDG directories
+#[derive(Debug)]
+pub struct Moved {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    pub result: io::Result<()>,
+}
+
+impl Dirs {
+    pub fn fetch() -> Result<Self, ActivityInsightsError> {
+        Self::from_env(|name| env::var_os(name), dirs::home_dir()).ok_or_else(|| {
+            ActivityInsightsError::Other(String::from("Can't find the home directory"))
+        })
+    }
+
+    fn from_env(var: impl Fn(&str) -> Option<OsString>, home: Option<PathBuf>) -> Option<Self> {
+        if let Some(dir) = var(constants::HOME_ENV_VAR).filter(|dir| !dir.is_empty()) {
+            return Some(Self::single(PathBuf::from(dir)));
+        }
+
+        let home = home?;
+        let legacy = home.join(constants::PS_DIR);
+        if !cfg!(target_os = "linux") {
+            return Some(Self::single(legacy));
+        }
+
+        // The spec says relative paths are invalid and should be ignored
+        let xdg = |name, default: &str| {
+            var(name)
+                .map(PathBuf::from)
+                .filter(|dir| dir.is_absolute())
+                .unwrap_or_else(|| home.join(default))
+                .join(constants::XDG_DIR_NAME)
+        };
+
+        Some(Dirs {
+            config: xdg("XDG_CONFIG_HOME", ".config"),
+            data: xdg("XDG_DATA_HOME", ".local/share"),
+            state: xdg("XDG_STATE_HOME", ".local/state"),
+            cache: xdg("XDG_CACHE_HOME", ".cache"),
+            install: legacy,
+        })
+    }
+
+    fn single(dir: PathBuf) -> Self {
+        Dirs {
+            config: dir.clone(),
+            data: dir.clone(),
+            state: dir.clone(),
+            cache: dir.clone(),
+            install: dir,
+        }
+    }
+
+    /// The directories holding credentials or activity, without duplicates
+    pub fn private(&self) -> Vec<&Path> {
+        let mut dirs = vec![self.data.as_path()];
+        if self.state != self.data {
+            dirs.push(&self.state);
+        }
+        dirs
+    }
+
+    /// Where the credentials are kept. Until they've been moved out of ~/.pluralsight they're
+    /// used from there, so a run that couldn't move them doesn't start over with new ones.
+    pub fn credentials(&self) -> &Path {
+        if self.data != self.install
+            && self.install.join(constants::CRED_FILE_NAME).exists()
+            && !has_content(&self.data.join(constants::CRED_FILE_NAME))
+        {
+            &self.install
+        } else {
+            &self.data
+        }
+    }
+
+    /// Moves the config, credentials, logs and pending pulses of an install from before the XDG
+    /// directories were used out of ~/.pluralsight. Files already in their new place are never
+    /// overwritten, unless they're empty, and the executable stays where it is.
+    pub fn migrate_legacy(&self) -> Vec<Moved> {
+        let legacy = &self.install;
+        if self.data == *legacy || !legacy.is_dir() {
+            return Vec::new();
+        }
+
+        let names: Vec<String> = match fs::read_dir(legacy) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .collect(),
+            Err(_) => return Vec::new(),
+        };
+
+        let targets: Vec<_> = names
+            .iter()
+            .filter_map(|name| Some((name, self.dir_for(name)?)))
+            .filter(|(name, dir)| !has_content(&dir.join(name)))
+            .collect();
+        if targets.is_empty() {
+            return Vec::new();
+        }
+
+        // Keeps an older cli from writing the credentials while they're moved. If it's holding on
+        // to the lock, the files are moved on a later run instead.
+        let _lock = match CredentialsGuard::new(&legacy.join(constants::LOCK_FILE_NAME)) {
+            Ok(lock) => lock,
+            Err(e) => {
+                warn!("Not moving the files out of {:?} this time: {}", legacy, e);
+                return Vec::new();
+            }
+        };
+        targets
+            .into_iter()
+            .map(|(name, dir)| {
+                let from = legacy.join(name);
+                let to = dir.join(name);
+                let result =
+                    permissions::create_private_dir(dir).and_then(|_| move_file(&from, &to));
+                Moved { from, to, result }
+            })
+            .collect()
+    }
+
+    /// The lock file is left behind, it's recreated next to the credentials
+    fn dir_for(&self, file_name: &str) -> Option<&PathBuf> {
+        if file_name == constants::CONFIG_FILE_NAME {
+            Some(&self.config)
+        } else if file_name == constants::LOCK_FILE_NAME {
+            None
+        } else if file_name.starts_with(constants::CRED_FILE_NAME) {
+            Some(&self.data)
+        } else if file_name == constants::LOG_FILE
+            || file_name.ends_with(constants::PENDING_PULSES_FILE)
+        {
+            Some(&self.state)
+        } else {
+            None
+        }
+    }
+}
+
+/// An empty file was created by reading it, not by moving it
+fn has_content(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|metadata| metadata.len() > 0)
+}
+
+/// Renames `from` to `to`, copying it over when they're on different filesystems. The copy is
+/// made next to `to` first so `to` is still replaced in a single step.
+pub(crate) fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+
+    let mut staged = to.as_os_str().to_owned();
+    staged.push(".staged");
+    let staged = PathBuf::from(staged);
+    fs::copy(from, &staged)
+        .and_then(|_| fs::rename(&staged, to))
+        .and_then(|_| fs::remove_file(from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn env(vars: &'static [(&'static str, &'static str)]) -> impl Fn(&str) -> Option<OsString> {
+        move |name| {
+            vars.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| OsString::from(value))
+        }
+    }
+
+    #[test]
+    fn home_override() {
+        let dirs = Dirs::from_env(env(&[(constants::HOME_ENV_VAR, "/opt/ai")]), None).unwrap();
+        assert_eq!(dirs, Dirs::single(PathBuf::from("/opt/ai")));
+        assert_eq!(dirs.private(), [Path::new("/opt/ai")]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn xdg_dirs() {
+        let home = Some(PathBuf::from("/home/me"));
+        let dirs = Dirs::from_env(
+            env(&[
+                ("XDG_CONFIG_HOME", "/etc/me"),
+                ("XDG_STATE_HOME", "relative/state"),
+            ]),
+            home,
+        )
+        .unwrap();
+
+        assert_eq!(dirs.config, Path::new("/etc/me/activity-insights"));
+        assert_eq!(
+            dirs.data,
+            Path::new("/home/me/.local/share/activity-insights")
+        );
+        assert_eq!(
+            dirs.state,
+            Path::new("/home/me/.local/state/activity-insights")
+        );
+        assert_eq!(dirs.cache, Path::new("/home/me/.cache/activity-insights"));
+        assert_eq!(dirs.install, Path::new("/home/me/.pluralsight"));
+    }
+
+    #[test]
+    fn migrates_legacy_files() {
+        let fake_home = tempdir().unwrap();
+        let legacy = fake_home.path().join(constants::PS_DIR);
+        fs::create_dir(&legacy).unwrap();
+        for name in &[
+            constants::CRED_FILE_NAME,
+            constants::LOCK_FILE_NAME,
+            constants::CONFIG_FILE_NAME,
+            constants::PENDING_PULSES_FILE,
+            constants::EXECUTABLE,
+        ] {
+            fs::write(legacy.join(name), name).unwrap();
+        }
+
+        let dirs = Dirs {
+            config: fake_home.path().join("config"),
+            data: fake_home.path().join("data"),
+            state: fake_home.path().join("state"),
+            cache: fake_home.path().join("cache"),
+            install: legacy.clone(),
+        };
+        fs::create_dir(&dirs.data).unwrap();
+        fs::write(dirs.data.join(constants::CRED_FILE_NAME), "newer").unwrap();
+
+        let moved = dirs.migrate_legacy();
+        assert!(moved.iter().all(|moved| moved.result.is_ok()));
+        assert_eq!(moved.len(), 2);
+
+        let read = |path: PathBuf| fs::read_to_string(path).unwrap();
+        assert_eq!(
+            read(dirs.config.join(constants::CONFIG_FILE_NAME)),
+            constants::CONFIG_FILE_NAME
+        );
+        assert_eq!(
+            read(dirs.state.join(constants::PENDING_PULSES_FILE)),
+            constants::PENDING_PULSES_FILE
+        );
+        assert_eq!(read(dirs.data.join(constants::CRED_FILE_NAME)), "newer");
+        assert!(legacy.join(constants::CRED_FILE_NAME).exists());
+        assert!(legacy.join(constants::EXECUTABLE).exists());
+
+        assert!(dirs.migrate_legacy().is_empty());
+    }
+
+    #[test]
+    fn waits_for_the_lock_to_migrate() {
+        let fake_home = tempdir().unwrap();
+        let legacy = fake_home.path().join(constants::PS_DIR);
+        fs::create_dir(&legacy).unwrap();
+        fs::write(legacy.join(constants::CRED_FILE_NAME), "in use").unwrap();
+
+        let dirs = Dirs {
+            config: fake_home.path().join("config"),
+            data: fake_home.path().join("data"),
+            state: fake_home.path().join("state"),
+            cache: fake_home.path().join("cache"),
+            install: legacy.clone(),
+        };
+
+        let lock = CredentialsGuard::new(&legacy.join(constants::LOCK_FILE_NAME)).unwrap();
+        assert!(dirs.migrate_legacy().is_empty());
+        assert!(legacy.join(constants::CRED_FILE_NAME).exists());
+
+        drop(lock);
+        assert_eq!(dirs.migrate_legacy().len(), 1);
+        assert!(dirs.data.join(constants::CRED_FILE_NAME).exists());
+    }
+
+    #[test]
+    fn migrates_after_a_skipped_run() {
+        let fake_home = tempdir().unwrap();
+        let legacy = fake_home.path().join(constants::PS_DIR);
+        fs::create_dir(&legacy).unwrap();
+        fs::write(legacy.join(constants::CRED_FILE_NAME), "registered").unwrap();
+
+        let dirs = Dirs {
+            config: fake_home.path().join("config"),
+            data: fake_home.path().join("data"),
+            state: fake_home.path().join("state"),
+            cache: fake_home.path().join("cache"),
+            install: legacy.clone(),
+        };
+
+        let lock = CredentialsGuard::new(&legacy.join(constants::LOCK_FILE_NAME)).unwrap();
+        assert!(dirs.migrate_legacy().is_empty());
+        assert_eq!(dirs.credentials(), legacy);
+        drop(lock);
+
+        // Left behind by a run from before the credentials stayed in ~/.pluralsight
+        fs::create_dir(&dirs.data).unwrap();
+        fs::write(dirs.data.join(constants::CRED_FILE_NAME), "").unwrap();
+        assert_eq!(dirs.credentials(), legacy);
+
+        assert_eq!(dirs.migrate_legacy().len(), 1);
+        assert_eq!(
+            fs::read_to_string(dirs.data.join(constants::CRED_FILE_NAME)).unwrap(),
+            "registered"
+        );
+        assert_eq!(dirs.credentials(), dirs.data);
+    }
+}
@@ -0,0 +1,153 @@
+use std::{
+    fs::{self, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::constants;
+
+/// Only the user can get into the .pluralsight directory
+pub const PRIVATE_DIR_MODE: u32 = 0o700;
+/// Only the user can read or write files with api tokens or activity in them
+pub const PRIVATE_FILE_MODE: u32 = 0o600;
+
+/// A file or directory other users can get at
+#[derive(Debug)]
+pub struct LoosePermissions {
+    pub path: PathBuf,
+    pub mode: u32,
+    pub expected: u32,
+}
+
+/// Creates `dir` and any missing parents readable by the user only. An existing directory is
+/// left as is.
+pub fn create_private_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(PRIVATE_DIR_MODE)
+            .create(dir)
+    }
+
+    #[cfg(not(unix))]
+    fs::create_dir_all(dir)
+}
+
+/// Files created through `options` are readable by the user only
+pub fn private(options: &mut OpenOptions) -> &mut OpenOptions {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(PRIVATE_FILE_MODE)
+    }
+
+    #[cfg(not(unix))]
+    options
+}
+
+pub fn restrict_file(path: &Path) -> io::Result<()> {
+    set_mode(path, PRIVATE_FILE_MODE)
+}
+
+/// Finds the .pluralsight directory and the files with secrets or activity in it that other users
+/// can read. Other platforms don't have unix permissions, so nothing is ever found there.
+pub fn find_loose(dir: &Path) -> io::Result<Vec<LoosePermissions>> {
+    let mut loose = Vec::new();
+    if !dir.exists() {
+        return Ok(loose);
+    }
+
+    loose.extend(check(dir, PRIVATE_DIR_MODE)?);
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if entry.file_type()?.is_file() && is_private(&name.to_string_lossy()) {
+            loose.extend(check(&entry.path(), PRIVATE_FILE_MODE)?);
+        }
+    }
+
+    Ok(loose)
+}
+
+pub fn fix(loose: &LoosePermissions) -> io::Result<()> {
+    set_mode(&loose.path, loose.expected)
+}
+
+/// The credentials, their lock, encrypted copy and corrupted backups, and the pulse queues
+fn is_private(file_name: &str) -> bool {
+    file_name.starts_with(constants::CRED_FILE_NAME)
+        || file_name.ends_with(constants::PENDING_PULSES_FILE)
+}
+
+#[cfg(unix)]
+fn check(path: &Path, expected: u32) -> io::Result<Option<LoosePermissions>> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = fs::metadata(path)?.permissions().mode() & 0o777;
+    Ok(if mode & 0o077 != 0 {
+        Some(LoosePermissions {
+            path: path.to_path_buf(),
+            mode,
+            expected,
+        })
+    } else {
+        None
+    })
+}
+
+#[cfg(not(unix))]
+fn check(_path: &Path, _expected: u32) -> io::Result<Option<LoosePermissions>> {
+    Ok(None)
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::tempdir;
+
+    #[test]
+    fn finds_and_fixes_loose_permissions() {
+        let fake_home = tempdir().unwrap();
+        let ps_dir = fake_home.path().join(constants::PS_DIR);
+        create_private_dir(&ps_dir).unwrap();
+
+        let creds_file = ps_dir.join(constants::CRED_FILE_NAME);
+        let log_file = ps_dir.join(constants::LOG_FILE);
+        fs::write(&creds_file, "api_token: ~\n").unwrap();
+        fs::write(&log_file, "").unwrap();
+        set_mode(&creds_file, 0o644).unwrap();
+        set_mode(&log_file, 0o644).unwrap();
+        set_mode(&ps_dir, 0o755).unwrap();
+
+        let loose = find_loose(&ps_dir).unwrap();
+        let found: Vec<_> = loose
+            .iter()
+            .map(|l| (&l.path, l.mode, l.expected))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (&ps_dir, 0o755, PRIVATE_DIR_MODE),
+                (&creds_file, 0o644, PRIVATE_FILE_MODE)
+            ]
+        );
+
+        loose.iter().for_each(|l| fix(l).unwrap());
+        assert!(find_loose(&ps_dir).unwrap().is_empty());
+        let mode = fs::metadata(&creds_file).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, PRIVATE_FILE_MODE);
+    }
+}
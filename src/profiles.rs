@@ -0,0 +1,134 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+use crate::{config::ProfileRule, constants, ActivityInsightsError, Config};
+
+/// Picks the profile pulses are sent under. A profile given on the command line or in the
+/// environment applies to every pulse, otherwise the directory rules from the config are matched
+/// against the file each pulse is for. Pulses no rule matches go to the default profile.
+#[derive(Debug, Clone, Default)]
+pub struct ProfileSelector {
+    selected: Option<String>,
+    rules: Vec<ProfileRule>,
+}
+
+impl ProfileSelector {
+    pub fn fetch(flag: Option<String>) -> Result<Self, ActivityInsightsError> {
+        Self::new(flag.or_else(from_env), Config::fetch()?.profiles)
+    }
+
+    fn new(
+        selected: Option<String>,
+        rules: Vec<ProfileRule>,
+    ) -> Result<Self, ActivityInsightsError> {
+        if let Some(name) = &selected {
+            validate(name)?;
+        }
+        for rule in &rules {
+            validate(&rule.profile)?;
+        }
+
+        Ok(ProfileSelector { selected, rules })
+    }
+
+    /// The profile from the command line or the environment
+    pub fn selected(&self) -> Option<&str> {
+        self.selected.as_deref()
+    }
+
+    /// When directories from several rules contain the path, the deepest one wins
+    pub fn for_path(&self, path: &Path) -> Option<&str> {
+        if self.selected.is_some() {
+            return self.selected();
+        }
+
+        self.rules
+            .iter()
+            .flat_map(|rule| rule.directories.iter().map(move |dir| (rule, dir)))
+            .filter(|(_, dir)| path.starts_with(expand_home(dir)))
+            .max_by_key(|(_, dir)| dir.components().count())
+            .map(|(rule, _)| rule.profile.as_str())
+    }
+}
+
+pub fn from_env() -> Option<String> {
+    env::var(constants::PROFILE_ENV_VAR)
+        .ok()
+        .filter(|name| !name.is_empty())
+}
+
+/// Profile names end up in file names, so they're kept to letters, digits, dashes and underscores
+pub fn validate(name: &str) -> Result<&str, ActivityInsightsError> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(name)
+    } else {
+        Err(ActivityInsightsError::InvalidProfile(name.to_string()))
+    }
+}
+
+fn expand_home(dir: &Path) -> PathBuf {
+    match (dir.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => dir.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(profile: &str, directories: &[&str]) -> ProfileRule {
+        ProfileRule {
+            profile: profile.to_string(),
+            directories: directories.iter().map(PathBuf::from).collect(),
+        }
+    }
+
+    #[test]
+    fn deepest_rule_wins() {
+        let selector = ProfileSelector::new(
+            None,
+            vec![
+                rule("work", &["/src/work"]),
+                rule("oss", &["/src/work/oss", "/src/github"]),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            selector.for_path(Path::new("/src/work/api/main.rs")),
+            Some("work")
+        );
+        assert_eq!(
+            selector.for_path(Path::new("/src/work/oss/lib.rs")),
+            Some("oss")
+        );
+        assert_eq!(
+            selector.for_path(Path::new("/src/github/lib.rs")),
+            Some("oss")
+        );
+        assert_eq!(selector.for_path(Path::new("/src/workshop/lib.rs")), None);
+
+        let selector =
+            ProfileSelector::new(Some("personal".into()), vec![rule("work", &["/src/work"])])
+                .unwrap();
+        assert_eq!(
+            selector.for_path(Path::new("/src/work/main.rs")),
+            Some("personal")
+        );
+    }
+
+    #[test]
+    fn profile_names() {
+        assert!(validate("work-2_b").is_ok());
+        assert!(validate("").is_err());
+        assert!(validate("../work").is_err());
+        assert!(ProfileSelector::new(None, vec![rule("a b", &["/src"])]).is_err());
+    }
+}
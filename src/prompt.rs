@@ -0,0 +1,81 @@
+use std::{
+    env,
+    io::{self, BufRead, IsTerminal, Write},
+    process::{Command, Stdio},
+};
+
+/// Someone is at a terminal to answer prompts. Editors run the cli with pipes for stdin and stdout
+pub fn is_interactive() -> bool {
+    io::stdin().is_terminal() && io::stdout().is_terminal()
+}
+
+/// Shows `text` through $PAGER, falling back to `less` and then to printing it all at once
+pub fn page(text: &str) {
+    let pager = env::var("PAGER")
+        .ok()
+        .filter(|pager| !pager.trim().is_empty())
+        .unwrap_or_else(|| String::from("less"));
+    let mut words = pager.split_whitespace();
+
+    let paged = words.next().and_then(|program| {
+        let mut child = Command::new(program)
+            .args(words)
+            .stdin(Stdio::piped())
+            .spawn()
+            .ok()?;
+        // The pager closing early, like quitting less before the end, isn't an error
+        let _ = child.stdin.take()?.write_all(text.as_bytes());
+        child.wait().ok()
+    });
+
+    if paged.is_none() {
+        println!("{}", text);
+    }
+}
+
+/// Asks `question` until it gets a yes or a no. Anything that ends the input counts as a no.
+pub fn confirm(
+    question: &str,
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> io::Result<bool> {
+    loop {
+        write!(output, "{} [y/n] ", question)?;
+        output.flush()?;
+
+        let mut answer = String::new();
+        if input.read_line(&mut answer)? == 0 {
+            writeln!(output)?;
+            return Ok(false);
+        }
+
+        match answer.trim().to_lowercase().as_str() {
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => writeln!(output, "Please answer yes or no")?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn confirm_answers() {
+        let ask = |input: &str| {
+            let mut output = Vec::new();
+            let answer = confirm("Accept?", &mut input.as_bytes(), &mut output).unwrap();
+            (answer, String::from_utf8(output).unwrap())
+        };
+
+        assert_eq!(ask("y\n"), (true, String::from("Accept? [y/n] ")));
+        assert!(ask("YES\n").0);
+        assert!(!ask("no\n").0);
+        assert!(!ask("").0);
+
+        let (answer, output) = ask("maybe\nyes\n");
+        assert!(answer);
+        assert_eq!(output.matches("Accept?").count(), 2);
+    }
+}
@@ -1,11 +1,15 @@
 use chrono::{TimeZone, Utc};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::collections::HashSet;
 use std::fs;
-use std::{convert::TryFrom, path::PathBuf};
+use std::{
+    convert::TryFrom,
+    path::{Path, PathBuf},
+};
 use thiserror::Error;
+use uuid::Uuid;
 
-use crate::constants;
+use crate::{constants, version};
 
 /// event_date is milliseconds seconds since the Unix epoch
 #[derive(Debug, Clone, Deserialize)]
@@ -18,17 +22,37 @@ pub struct PulseFromEditor {
 }
 
 /// date is a string representing a date formatted according to: https://tools.ietf.org/html/rfc3339
-#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+///
+/// Pulses are deserialized when they're read back from the queue of pulses that failed to send
+///
+/// id is derived from the editor event, so the same event always produces the same id no matter
+/// how many times it's sent
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 pub struct Pulse {
-    #[serde(rename(serialize = "type"))]
+    // Pulses queued before pulses had ids get a random one
+    #[serde(default = "Uuid::new_v4")]
+    id: Uuid,
+    #[serde(rename = "type")]
     pulse_type: String,
     date: String,
-    #[serde(rename(serialize = "programmingLanguage"))]
+    #[serde(rename = "programmingLanguage")]
     programming_language: String,
     editor: String,
+    #[serde(deserialize_with = "deserialize_tags")]
     tags: HashSet<&'static str>,
-    #[serde(rename(serialize = "cliVersion"))]
-    cli_version: usize,
+    /// The api only takes an integer, so the major version is sent until it accepts semver
+    #[serde(rename = "cliVersion")]
+    cli_version: u64,
+}
+
+/// What an editor event turns into, as shown by `inspect`. Events that can't be read or converted
+/// have an error instead of a language.
+#[derive(Debug)]
+pub struct Inspection {
+    pub file_path: Option<PathBuf>,
+    pub language: Option<String>,
+    pub libraries: Vec<&'static str>,
+    pub error: Option<String>,
 }
 
 #[derive(Debug, Error)]
@@ -37,6 +61,12 @@ pub enum ConversionError {
     IOError(PathBuf, std::io::Error),
 }
 
+impl PulseFromEditor {
+    pub fn file_path(&self) -> &Path {
+        &self.file_path
+    }
+}
+
 /// TryFrom will fail in the event of an io error but not if the programming language can't be
 /// detected. If the programming language can't be detected, then "Other" will be the  value of
 /// programming language
@@ -55,17 +85,115 @@ impl TryFrom<PulseFromEditor> for Pulse {
         let content = fs::read_to_string(&editor_pulse.file_path).unwrap_or_default();
         let tags = super::get_libraries(&content);
 
+        let id = pulse_id(
+            &editor_pulse.editor,
+            &editor_pulse.file_path,
+            &editor_pulse.event_type,
+            editor_pulse.event_date,
+        );
+
         Ok(Pulse {
+            id,
             pulse_type: editor_pulse.event_type,
             date: timestamp.to_rfc3339(),
             editor: editor_pulse.editor,
             programming_language: String::from(language),
             tags,
-            cli_version: constants::VERSION,
+            cli_version: version::cli_version().major,
         })
     }
 }
 
+impl Inspection {
+    /// Goes through the same conversion the pulse command uses
+    pub fn of(event: serde_json::Value) -> Self {
+        let file_path = event
+            .get("filePath")
+            .and_then(serde_json::Value::as_str)
+            .map(PathBuf::from);
+        let pulse = serde_json::from_value::<PulseFromEditor>(event)
+            .map_err(|e| e.to_string())
+            .and_then(|event| Pulse::try_from(event).map_err(|e| e.to_string()));
+
+        match pulse {
+            Ok(pulse) => {
+                let mut libraries: Vec<_> = pulse.tags.into_iter().collect();
+                libraries.sort_unstable();
+                Inspection {
+                    file_path,
+                    language: Some(pulse.programming_language),
+                    libraries,
+                    error: None,
+                }
+            }
+            Err(e) => Inspection {
+                file_path,
+                language: None,
+                libraries: Vec::new(),
+                error: Some(e),
+            },
+        }
+    }
+}
+
+/// A name based uuid of the event. The path is hashed on its own first so the raw path isn't part
+/// of the name.
+fn pulse_id(editor: &str, path: &Path, event_type: &str, event_date: i64) -> Uuid {
+    let path_hash = Uuid::new_v5(
+        &constants::PULSE_ID_NAMESPACE,
+        path.to_string_lossy().as_bytes(),
+    );
+    let name = format!("{}\n{}\n{}\n{}", editor, path_hash, event_type, event_date);
+    Uuid::new_v5(&constants::PULSE_ID_NAMESPACE, name.as_bytes())
+}
+
+/// The Idempotency-Key for a request, derived from the ids of the pulses in it so retrying or
+/// replaying the same pulses reuses the key
+pub fn idempotency_key(pulses: &[Pulse]) -> Uuid {
+    let ids: Vec<u8> = pulses
+        .iter()
+        .flat_map(|pulse| pulse.id.as_bytes().to_vec())
+        .collect();
+    Uuid::new_v5(&constants::PULSE_ID_NAMESPACE, &ids)
+}
+
+/// Tags are always one of the known packages, so they can be mapped back to the static names
+fn deserialize_tags<'de, D>(deserializer: D) -> Result<HashSet<&'static str>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let tags: Vec<String> = Vec::deserialize(deserializer)?;
+    Ok(tags
+        .iter()
+        .filter_map(|tag| super::PACKAGES.get_key(tag.as_str()).copied())
+        .collect())
+}
+
+/// Splits pulses into runs of at most `max_pulses` whose json takes up at most roughly `max_bytes`.
+/// A single pulse bigger than `max_bytes` still gets a chunk of its own.
+pub fn chunk_pulses(pulses: &[Pulse], max_pulses: usize, max_bytes: usize) -> Vec<&[Pulse]> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut bytes = 0;
+
+    for (i, pulse) in pulses.iter().enumerate() {
+        // The extra byte is for the comma separating pulses in the array
+        let size = serde_json::to_vec(pulse).map_or(0, |json| json.len()) + 1;
+        let count = i - start;
+        if count > 0 && (count >= max_pulses || bytes + size > max_bytes) {
+            chunks.push(&pulses[start..i]);
+            start = i;
+            bytes = 0;
+        }
+        bytes += size;
+    }
+
+    if start < pulses.len() {
+        chunks.push(&pulses[start..]);
+    }
+    chunks
+}
+
 /// Takes a unix timestamp in ms and breaks it down into the number of seconds and nano seconds.
 /// This is the way chrono expects the time when generating a Utc timestamp and it comes out of the
 /// editors in ms
@@ -102,16 +230,88 @@ mod tests {
         let tags: HashSet<&'static str> = vec!["express", "reqwest"].into_iter().collect();
 
         let expected = Pulse {
+            id: pulse_id("emacs :rip:", fake_file.path(), "typing", 1595868513238),
             pulse_type: String::from("typing"),
             date: String::from("2020-07-27T16:48:33.238+00:00"),
             programming_language: String::from("Other"),
             editor: String::from("emacs :rip:"),
             tags,
-            cli_version: constants::VERSION,
+            cli_version: version::cli_version().major,
         };
         assert_eq!(pulse, expected);
     }
 
+    #[test]
+    fn inspecting_events() {
+        let mut fake_file = NamedTempFile::new().unwrap();
+        write!(fake_file, "use reqwest;\nuse serde;\n").unwrap();
+        let path = fake_file.path().to_str().unwrap();
+
+        let inspection = Inspection::of(serde_json::json!({
+            "filePath": path,
+            "eventType": "typing",
+            "eventDate": 1595868513238i64,
+            "editor": "vim",
+        }));
+        assert_eq!(inspection.file_path.as_deref(), Some(fake_file.path()));
+        assert_eq!(inspection.language.as_deref(), Some("Other"));
+        assert_eq!(inspection.libraries, ["reqwest", "serde"]);
+        assert!(inspection.error.is_none());
+
+        let inspection = Inspection::of(serde_json::json!({
+            "filePath": path,
+            "eventType": "typing",
+        }));
+        assert!(inspection.language.is_none());
+        assert!(inspection.error.unwrap().contains("missing field"));
+    }
+
+    #[test]
+    fn chunking() {
+        let pulse = Pulse {
+            id: Uuid::new_v4(),
+            pulse_type: String::from("typing"),
+            date: String::from("2020-07-27T16:48:33.238+00:00"),
+            programming_language: String::from("Rust"),
+            editor: String::from("vim"),
+            tags: HashSet::new(),
+            cli_version: version::cli_version().major,
+        };
+        let size = serde_json::to_vec(&pulse).unwrap().len() + 1;
+        let pulses = vec![pulse; 5];
+
+        let by_count: Vec<usize> = chunk_pulses(&pulses, 2, usize::MAX)
+            .iter()
+            .map(|chunk| chunk.len())
+            .collect();
+        assert_eq!(by_count, [2, 2, 1]);
+
+        let by_size: Vec<usize> = chunk_pulses(&pulses, 100, size * 3)
+            .iter()
+            .map(|chunk| chunk.len())
+            .collect();
+        assert_eq!(by_size, [3, 2]);
+
+        let oversized: Vec<usize> = chunk_pulses(&pulses, 100, 1)
+            .iter()
+            .map(|chunk| chunk.len())
+            .collect();
+        assert_eq!(oversized, [1, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn pulse_ids_are_deterministic() {
+        let path = Path::new("src/main.rs");
+        let id = pulse_id("vim", path, "typing", 1595868513238);
+
+        assert_eq!(id, pulse_id("vim", path, "typing", 1595868513238));
+        assert_ne!(id, pulse_id("vim", path, "typing", 1595868513239));
+        assert_ne!(
+            id,
+            pulse_id("vim", Path::new("src/lib.rs"), "typing", 1595868513238)
+        );
+    }
+
     #[test]
     fn breakdown_milliseconds_smoke_test() {
         assert_eq!(breakdown_milliseconds(10_500), (10, 500_000_000))
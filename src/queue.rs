@@ -0,0 +1,163 @@
+use log::warn;
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+use uuid::Uuid;
+
+use crate::{constants, paths::Dirs, permissions, pulses::Pulse, ActivityInsightsError};
+
+/// Pulses that couldn't be delivered, kept on disk as one json object per line so they can go out
+/// with the next batch. Each profile has a queue of its own.
+#[derive(Debug)]
+pub struct PulseQueue {
+    path: PathBuf,
+}
+
+impl PulseQueue {
+    pub fn open(profile: Option<&str>) -> Result<Self, ActivityInsightsError> {
+        let queue_dir = Dirs::fetch()?.state;
+        permissions::create_private_dir(&queue_dir)
+            .map_err(|e| ActivityInsightsError::IO(queue_dir.clone(), e))?;
+
+        Ok(Self::in_dir(&queue_dir, profile))
+    }
+
+    pub(crate) fn in_dir(dir: &Path, profile: Option<&str>) -> Self {
+        let path = match profile {
+            Some(name) => dir.join(format!("{}.{}", name, constants::PENDING_PULSES_FILE)),
+            None => dir.join(constants::PENDING_PULSES_FILE),
+        };
+        PulseQueue { path }
+    }
+
+    pub fn push(&self, pulses: &[Pulse]) -> Result<(), ActivityInsightsError> {
+        if pulses.is_empty() {
+            return Ok(());
+        }
+
+        let mut file = permissions::private(&mut OpenOptions::new())
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| ActivityInsightsError::IO(self.path.clone(), e))?;
+
+        let mut lines = Vec::new();
+        for pulse in pulses {
+            serde_json::to_writer(&mut lines, pulse)?;
+            lines.push(b'\n');
+        }
+
+        // A single write keeps lines from concurrent processes from interleaving
+        file.write_all(&lines)
+            .map_err(|e| ActivityInsightsError::IO(self.path.clone(), e))
+    }
+
+    /// How many pulses are waiting, without taking them
+    pub fn count(&self) -> Result<usize, ActivityInsightsError> {
+        match fs::File::open(&self.path) {
+            Ok(file) => Ok(BufReader::new(file)
+                .lines()
+                .map_while(Result::ok)
+                .filter(|line| !line.trim().is_empty())
+                .count()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(ActivityInsightsError::IO(self.path.clone(), e)),
+        }
+    }
+
+    /// Drops every pending pulse without sending it
+    pub fn clear(&self) -> Result<(), ActivityInsightsError> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                Err(ActivityInsightsError::IO(self.path.clone(), e))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Removes every pulse from the queue and returns them, oldest first. Only the newest
+    /// MAX_PENDING_PULSES are kept so a long outage can't grow the file forever.
+    pub fn take(&self) -> Result<Vec<Pulse>, ActivityInsightsError> {
+        // Moving the file out of the way first means another process taking from the queue at the
+        // same time can't read the same pulses
+        let claimed = self.path.with_extension(Uuid::new_v4().to_string());
+        match fs::rename(&self.path, &claimed) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(ActivityInsightsError::IO(self.path.clone(), e)),
+        }
+
+        let file =
+            fs::File::open(&claimed).map_err(|e| ActivityInsightsError::IO(claimed.clone(), e))?;
+        let mut pulses: Vec<Pulse> = BufReader::new(file)
+            .lines()
+            .filter_map(|line| match line.map(|line| serde_json::from_str(&line)) {
+                Ok(Ok(pulse)) => Some(pulse),
+                Ok(Err(e)) => {
+                    warn!("Dropping unreadable pending pulse: {}", e);
+                    None
+                }
+                Err(e) => {
+                    warn!("Error reading pending pulses: {}", e);
+                    None
+                }
+            })
+            .collect();
+
+        if let Err(e) = fs::remove_file(&claimed) {
+            warn!("Unable to remove claimed pulses {:?}: {}", claimed, e);
+        }
+
+        if pulses.len() > constants::MAX_PENDING_PULSES {
+            let dropped = pulses.len() - constants::MAX_PENDING_PULSES;
+            warn!("Dropping {} of the oldest pending pulses", dropped);
+            pulses.drain(..dropped);
+        }
+
+        Ok(pulses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build_pulses;
+    use tempfile::tempdir;
+
+    #[test]
+    fn push_then_take() {
+        let fake_dir = tempdir().unwrap();
+        let queue = PulseQueue::in_dir(fake_dir.path(), None);
+
+        let fake_file = fake_dir.path().join("main.rs");
+        fs::write(&fake_file, "use reqwest;").unwrap();
+        let raw_pulses = r#"[{"filePath": "{filepath}","eventType": "typing","eventDate": 1595868513238,"editor": "vim"}]"#
+            .replace("{filepath}", fake_file.to_str().unwrap());
+        #[cfg(windows)]
+        let raw_pulses = raw_pulses.replace(r#"\"#, r#"\\"#);
+        let pulses = build_pulses(&raw_pulses).unwrap();
+        assert_eq!(pulses.len(), 1);
+
+        queue.push(&pulses).unwrap();
+        queue.push(&pulses).unwrap();
+        assert_eq!(queue.count().unwrap(), 2);
+
+        assert_eq!(
+            queue.take().unwrap(),
+            [pulses.clone(), pulses.clone()].concat()
+        );
+        assert!(queue.take().unwrap().is_empty());
+
+        let work_queue = PulseQueue::in_dir(fake_dir.path(), Some("work"));
+        work_queue.push(&pulses).unwrap();
+        assert!(queue.take().unwrap().is_empty());
+        assert_eq!(work_queue.take().unwrap(), pulses);
+
+        queue.push(&pulses).unwrap();
+        queue.clear().unwrap();
+        queue.clear().unwrap();
+        assert!(queue.take().unwrap().is_empty());
+    }
+}
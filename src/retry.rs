@@ -0,0 +1,153 @@
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+/// What the caller wants to happen after an attempt
+#[derive(Debug)]
+pub enum Attempt<T, E> {
+    Done(T),
+    /// Try again, after the given delay if the server asked for one
+    Retry(E, Option<Duration>),
+    Fail(E),
+}
+
+/// Full jitter exponential backoff: the nth retry waits a random amount of time between zero and
+/// `base * 2^n`, capped at `max_delay`. Retries stop once the next wait would take the total time
+/// spent over `budget`.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub base: Duration,
+    pub max_delay: Duration,
+    pub budget: Duration,
+}
+
+impl Backoff {
+    fn delay(&self, retry: u32) -> Duration {
+        let ceiling = self
+            .base
+            .checked_mul(2u32.saturating_pow(retry))
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay));
+        rand::thread_rng().gen_range(Duration::from_millis(0), ceiling + Duration::from_millis(1))
+    }
+
+    /// `attempt` is handed the time left in the budget, so a single slow attempt can be kept from
+    /// running past it
+    pub fn run<T, E>(&self, attempt: impl FnMut(Duration) -> Attempt<T, E>) -> Result<T, E> {
+        self.run_until(Instant::now() + self.budget, attempt)
+    }
+
+    /// Like `run`, but the budget ends at `deadline` so several calls can share it
+    pub fn run_until<T, E>(
+        &self,
+        deadline: Instant,
+        mut attempt: impl FnMut(Duration) -> Attempt<T, E>,
+    ) -> Result<T, E> {
+        let mut retry = 0;
+        loop {
+            match attempt(deadline.saturating_duration_since(Instant::now())) {
+                Attempt::Done(value) => return Ok(value),
+                Attempt::Fail(e) => return Err(e),
+                Attempt::Retry(e, retry_after) => {
+                    let delay = retry_after.unwrap_or_else(|| self.delay(retry));
+                    if Instant::now() + delay > deadline {
+                        return Err(e);
+                    }
+                    thread::sleep(delay);
+                    retry += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Retry-After is either a number of seconds or an HTTP date
+pub fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    Some(
+        date.with_timezone(&Utc)
+            .signed_duration_since(now)
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const BACKOFF: Backoff = Backoff {
+        base: Duration::from_millis(1),
+        max_delay: Duration::from_millis(4),
+        budget: Duration::from_millis(50),
+    };
+
+    #[test]
+    fn retries_until_done() {
+        let mut attempts = 0;
+        let mut remaining = Vec::new();
+        let result: Result<usize, ()> = BACKOFF.run(|left| {
+            attempts += 1;
+            remaining.push(left);
+            if attempts < 3 {
+                Attempt::Retry((), None)
+            } else {
+                Attempt::Done(attempts)
+            }
+        });
+        assert_eq!(result, Ok(3));
+        assert!(remaining[0] <= BACKOFF.budget);
+        assert!(remaining.windows(2).all(|pair| pair[1] <= pair[0]));
+    }
+
+    #[test]
+    fn gives_up_when_retry_after_exceeds_budget() {
+        let mut attempts = 0;
+        let result: Result<(), &str> = BACKOFF.run(|_| {
+            attempts += 1;
+            Attempt::Retry("slow down", Some(Duration::from_secs(60)))
+        });
+        assert_eq!((result, attempts), (Err("slow down"), 1));
+    }
+
+    #[test]
+    fn shares_a_deadline() {
+        let deadline = Instant::now() + BACKOFF.budget;
+        let result: Result<(), &str> = BACKOFF.run_until(deadline, |_| {
+            thread::sleep(BACKOFF.budget);
+            Attempt::Retry("down", None)
+        });
+        assert_eq!(result, Err("down"));
+
+        let mut remaining = Vec::new();
+        let result: Result<(), &str> = BACKOFF.run_until(deadline, |left| {
+            remaining.push(left);
+            Attempt::Retry("still down", None)
+        });
+        assert_eq!(
+            (result, remaining),
+            (Err("still down"), vec![Duration::from_secs(0)])
+        );
+    }
+
+    #[test]
+    fn retry_after_formats() {
+        let now = Utc.ymd(2015, 10, 21).and_hms(7, 28, 0);
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+}
@@ -0,0 +1,360 @@
+use log::{info, warn};
+use reqwest::{
+    blocking::{Client, Response},
+    header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE, RETRY_AFTER},
+    StatusCode,
+};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+use crate::{
+    constants, http,
+    paths::Dirs,
+    pulses::{self, Pulse},
+    retry::{self, Attempt, Backoff},
+    ActivityInsightsError, PulseRequest,
+};
+
+/// Sized so that a batch which can't be delivered gives up before the editor stops waiting on us.
+/// The budget is shared by every request of a run through `deadline`.
+const BACKOFF: Backoff = Backoff {
+    base: Duration::from_millis(constants::PULSE_RETRY_BASE_MS),
+    max_delay: Duration::from_millis(constants::PULSE_RETRY_MAX_DELAY_MS),
+    budget: Duration::from_millis(constants::PULSE_RETRY_BUDGET_MS),
+};
+
+/// Posts pulses to the api. Bodies are sent uncompressed unless the config turns gzip on, or the
+/// config leaves it unset and the api says it accepts gzip through an Accept-Encoding header.
+/// The api saying so is remembered for a while in the state directory, so later runs compress
+/// their first request too.
+pub struct PulseSender {
+    client: Client,
+    url: String,
+    token: Uuid,
+    gzip_setting: Option<bool>,
+    gzip: bool,
+    request_timeout: Duration,
+    gzip_support_file: PathBuf,
+}
+
+impl PulseSender {
+    pub fn new(token: Uuid) -> Result<Self, ActivityInsightsError> {
+        let config = crate::Config::fetch()?;
+        let request_timeout = config
+            .http
+            .request_timeout_secs
+            .unwrap_or(constants::REQUEST_TIMEOUT_SECS);
+        Ok(Self::with_client(
+            http::client()?,
+            constants::PULSE_API_URL,
+            token,
+            config.pulses.gzip,
+            Duration::from_secs(request_timeout),
+            Dirs::fetch()?.state.join(constants::GZIP_SUPPORT_FILE),
+        ))
+    }
+
+    fn with_client(
+        client: Client,
+        url: &str,
+        token: Uuid,
+        gzip_setting: Option<bool>,
+        request_timeout: Duration,
+        gzip_support_file: PathBuf,
+    ) -> Self {
+        PulseSender {
+            client,
+            url: url.to_string(),
+            token,
+            gzip_setting,
+            gzip: gzip_setting.unwrap_or_else(|| recently_accepted_gzip(&gzip_support_file)),
+            request_timeout,
+            gzip_support_file,
+        }
+    }
+
+    /// When the retries of every request sent during this run have to be over
+    pub fn deadline() -> Instant {
+        Instant::now() + BACKOFF.budget
+    }
+
+    pub fn send(
+        &mut self,
+        pulses: &[Pulse],
+        deadline: Instant,
+    ) -> Result<StatusCode, ActivityInsightsError> {
+        let idempotency_key = pulses::idempotency_key(pulses);
+        BACKOFF.run_until(deadline, |remaining| {
+            self.post(pulses, &idempotency_key, remaining)
+        })
+    }
+
+    /// Connection errors, timeouts, 5xx and 429 are worth trying again. Any other response is
+    /// final, except for a gzipped body being turned down which is tried again uncompressed. The
+    /// request times out once the retry budget runs out, so the editor isn't left waiting on it.
+    fn post(
+        &mut self,
+        pulses: &[Pulse],
+        idempotency_key: &Uuid,
+        remaining: Duration,
+    ) -> Attempt<StatusCode, ActivityInsightsError> {
+        let url = self.url.clone();
+        let body = match serde_json::to_vec(&PulseRequest::new(pulses)) {
+            Ok(body) => body,
+            Err(e) => return Attempt::Fail(e.into()),
+        };
+
+        let request = self
+            .client
+            .post(&url)
+            .timeout(remaining.min(self.request_timeout))
+            .bearer_auth(self.token)
+            .header(CONTENT_TYPE, "application/json")
+            .header("Idempotency-Key", idempotency_key.to_string());
+        let request = if self.gzip {
+            match http::gzip(&body) {
+                Ok(compressed) => request.header(CONTENT_ENCODING, "gzip").body(compressed),
+                Err(e) => return Attempt::Fail(ActivityInsightsError::Other(e.to_string())),
+            }
+        } else {
+            request.body(body)
+        };
+
+        let res = match request.send() {
+            Ok(res) => res,
+            Err(e) if e.is_request() || e.is_timeout() => {
+                return Attempt::Retry(ActivityInsightsError::HTTP(url.to_string(), e), None)
+            }
+            Err(e) => return Attempt::Fail(ActivityInsightsError::HTTP(url.to_string(), e)),
+        };
+
+        let status = res.status();
+        if status == StatusCode::UNSUPPORTED_MEDIA_TYPE && self.gzip {
+            info!("Api turned down a gzipped body, falling back to uncompressed");
+            self.gzip = false;
+            match fs::remove_file(&self.gzip_support_file) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    warn!("Unable to forget that the api accepts gzip: {}", e)
+                }
+                _ => (),
+            }
+            return Attempt::Retry(
+                ActivityInsightsError::BadResponse(url.to_string(), status),
+                Some(Duration::from_secs(0)),
+            );
+        }
+
+        if self.gzip_setting.is_none() && !self.gzip && accepts_gzip(&res) {
+            self.gzip = true;
+            if let Err(e) = fs::write(&self.gzip_support_file, "") {
+                warn!("Unable to remember that the api accepts gzip: {}", e);
+            }
+        }
+
+        if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+            let retry_after = res
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| retry::parse_retry_after(value, chrono::Utc::now()));
+            return Attempt::Retry(
+                ActivityInsightsError::BadResponse(url.to_string(), status),
+                retry_after,
+            );
+        }
+
+        Attempt::Done(status)
+    }
+}
+
+/// The file is rewritten each time a run learns the api accepts gzip, so its age is how long ago
+/// the api last said so
+fn recently_accepted_gzip(gzip_support_file: &Path) -> bool {
+    fs::metadata(gzip_support_file)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|age| age < Duration::from_secs(constants::GZIP_SUPPORT_TTL_SECS))
+}
+
+fn accepts_gzip(res: &Response) -> bool {
+    res.headers()
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| coding.trim().eq_ignore_ascii_case("gzip"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::{
+        io::{Read, Write},
+        net::{Ipv4Addr, TcpListener, TcpStream},
+        thread::{self, JoinHandle},
+    };
+    use tempfile::{tempdir, TempDir};
+
+    /// A request as the api saw it
+    struct Received {
+        content_encoding: Option<String>,
+        body: Vec<u8>,
+    }
+
+    /// Answers one request per response, in order, and hands back what it received
+    fn api(responses: &'static [&'static str]) -> (String, JoinHandle<Vec<Received>>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let url = format!("http://{}/pulses", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            responses
+                .iter()
+                .map(|response| {
+                    let (mut stream, _) = listener.accept().unwrap();
+                    let received = read_request(&mut stream);
+                    write!(
+                        stream,
+                        "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        response
+                    )
+                    .unwrap();
+                    received
+                })
+                .collect()
+        });
+        (url, server)
+    }
+
+    fn read_request(stream: &mut TcpStream) -> Received {
+        let mut request = Vec::new();
+        let mut buffer = [0; 1024];
+        let headers_end = loop {
+            if let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+                break end + 4;
+            }
+            let read = stream.read(&mut buffer).unwrap();
+            request.extend_from_slice(&buffer[..read]);
+        };
+
+        let headers = String::from_utf8_lossy(&request[..headers_end]).to_lowercase();
+        let header = |name: &str| {
+            headers
+                .lines()
+                .find_map(|line| line.strip_prefix(&format!("{}: ", name)))
+                .map(String::from)
+        };
+        let length: usize = header("content-length").unwrap().parse().unwrap();
+        while request.len() < headers_end + length {
+            let read = stream.read(&mut buffer).unwrap();
+            request.extend_from_slice(&buffer[..read]);
+        }
+
+        Received {
+            content_encoding: header("content-encoding"),
+            body: request[headers_end..].to_vec(),
+        }
+    }
+
+    fn sender(url: &str, gzip_setting: Option<bool>, state_dir: &TempDir) -> PulseSender {
+        PulseSender::with_client(
+            Client::new(),
+            url,
+            Uuid::new_v4(),
+            gzip_setting,
+            Duration::from_secs(5),
+            state_dir.path().join(constants::GZIP_SUPPORT_FILE),
+        )
+    }
+
+    fn pulses() -> Vec<Pulse> {
+        serde_json::from_str(
+            r#"[{"type": "typing", "date": "2020-07-27T16:48:33.238+00:00", "programmingLanguage": "Rust", "editor": "vim", "tags": [], "cliVersion": 5}]"#,
+        )
+        .unwrap()
+    }
+
+    fn gunzip(body: &[u8]) -> Vec<u8> {
+        let mut decompressed = Vec::new();
+        GzDecoder::new(body).read_to_end(&mut decompressed).unwrap();
+        decompressed
+    }
+
+    #[test]
+    fn gzips_once_the_api_accepts_it() {
+        let (url, api) = api(&["204 No Content\r\nAccept-Encoding: gzip", "204 No Content"]);
+        let state_dir = tempdir().unwrap();
+        let mut sender = sender(&url, None, &state_dir);
+        let pulses = pulses();
+
+        assert_eq!(
+            sender.send(&pulses, PulseSender::deadline()).unwrap(),
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            sender.send(&pulses, PulseSender::deadline()).unwrap(),
+            StatusCode::NO_CONTENT
+        );
+
+        let received = api.join().unwrap();
+        assert_eq!(received[0].content_encoding, None);
+        assert_eq!(received[1].content_encoding.as_deref(), Some("gzip"));
+        assert_eq!(gunzip(&received[1].body), received[0].body);
+    }
+
+    #[test]
+    fn remembers_that_the_api_accepts_gzip() {
+        let (url, api) = api(&["204 No Content\r\nAccept-Encoding: gzip", "204 No Content"]);
+        let state_dir = tempdir().unwrap();
+        sender(&url, None, &state_dir)
+            .send(&pulses(), PulseSender::deadline())
+            .unwrap();
+        sender(&url, None, &state_dir)
+            .send(&pulses(), PulseSender::deadline())
+            .unwrap();
+
+        let received = api.join().unwrap();
+        assert_eq!(received[0].content_encoding, None);
+        assert_eq!(received[1].content_encoding.as_deref(), Some("gzip"));
+    }
+
+    #[test]
+    fn gzip_setting_wins_over_the_api() {
+        let (url, api) = api(&["204 No Content\r\nAccept-Encoding: gzip", "204 No Content"]);
+        let state_dir = tempdir().unwrap();
+        let mut sender = sender(&url, Some(false), &state_dir);
+        let pulses = pulses();
+
+        sender.send(&pulses, PulseSender::deadline()).unwrap();
+        sender.send(&pulses, PulseSender::deadline()).unwrap();
+
+        let received = api.join().unwrap();
+        assert!(received
+            .iter()
+            .all(|request| request.content_encoding.is_none()));
+    }
+
+    #[test]
+    fn falls_back_to_uncompressed_when_gzip_is_turned_down() {
+        let (url, api) = api(&["415 Unsupported Media Type", "204 No Content"]);
+        let state_dir = tempdir().unwrap();
+        fs::write(state_dir.path().join(constants::GZIP_SUPPORT_FILE), "").unwrap();
+        let mut sender = sender(&url, None, &state_dir);
+
+        assert_eq!(
+            sender.send(&pulses(), PulseSender::deadline()).unwrap(),
+            StatusCode::NO_CONTENT
+        );
+
+        let received = api.join().unwrap();
+        assert_eq!(received[0].content_encoding.as_deref(), Some("gzip"));
+        assert_eq!(received[1].content_encoding, None);
+        assert_eq!(gunzip(&received[0].body), received[1].body);
+        assert!(serde_json::from_slice::<serde_json::Value>(&received[1].body).is_ok());
+        assert!(!state_dir.path().join(constants::GZIP_SUPPORT_FILE).exists());
+    }
+}
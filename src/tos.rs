@@ -0,0 +1,127 @@
+use chrono::{DateTime, Utc};
+use ring::digest;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+
+use crate::{constants, version};
+
+/// A record of the user accepting a version of the terms of service
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct TosAcceptance {
+    #[serde(deserialize_with = "crate::version::deserialize")]
+    pub version: Version,
+    /// The rest is unset for acceptances recorded before they were kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accepted_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cli_version: Option<Version>,
+    /// Hex encoded sha256 of the text of the terms the user was shown
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tos_sha256: Option<String>,
+}
+
+impl TosAcceptance {
+    /// Accepting the terms bundled with this cli, now
+    pub fn bundled() -> Self {
+        TosAcceptance {
+            version: version::tos_version(),
+            accepted_at: Some(Utc::now()),
+            cli_version: Some(version::cli_version()),
+            tos_sha256: Some(sha256(constants::TOS)),
+        }
+    }
+
+    /// An acceptance of `version` with nothing else known about it
+    pub fn of_version(version: Version) -> Self {
+        TosAcceptance {
+            version,
+            accepted_at: None,
+            cli_version: None,
+            tos_sha256: None,
+        }
+    }
+
+    /// Whether the text the user accepted is known to differ from the bundled text of the same
+    /// version
+    pub fn text_changed(&self) -> bool {
+        self.version == version::tos_version()
+            && self
+                .tos_sha256
+                .as_ref()
+                .is_some_and(|hash| *hash != sha256(constants::TOS))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct TosChange {
+    #[serde(deserialize_with = "crate::version::deserialize")]
+    pub version: Version,
+    pub summary: String,
+}
+
+impl TosChange {
+    /// The changes to the terms after `accepted`, oldest first. Every change is listed when
+    /// nothing was accepted.
+    pub fn since(accepted: Option<&Version>) -> Vec<TosChange> {
+        changes_after(parse_changes(constants::TOS_CHANGES), accepted)
+    }
+}
+
+fn changes_after(mut changes: Vec<TosChange>, accepted: Option<&Version>) -> Vec<TosChange> {
+    changes.retain(|change| accepted.is_none_or(|accepted| change.version > *accepted));
+    changes.sort_by(|a, b| a.version.cmp(&b.version));
+    changes
+}
+
+fn parse_changes(content: &str) -> Vec<TosChange> {
+    serde_yaml::from_str(content).expect("terms-of-service-changes is not a valid changelog")
+}
+
+pub fn sha256(text: &str) -> String {
+    digest::digest(&digest::SHA256, text.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundled_changelog() {
+        let changes = parse_changes(constants::TOS_CHANGES);
+        assert!(changes
+            .iter()
+            .any(|change| change.version == version::tos_version()));
+        assert!(TosChange::since(Some(&version::tos_version())).is_empty());
+    }
+
+    #[test]
+    fn changes_since_accepted() {
+        let changes = parse_changes(
+            "- version: 2.0.0\n  summary: two\n- version: 1.1.0\n  summary: one\n- version: 1.0.0\n  summary: first\n",
+        );
+        assert_eq!(changes_after(changes.clone(), None).len(), 3);
+
+        let since = changes_after(changes, Some(&Version::new(1, 0, 0)));
+        assert_eq!(
+            since.iter().map(|c| c.summary.as_str()).collect::<Vec<_>>(),
+            ["one", "two"]
+        );
+    }
+
+    #[test]
+    fn hashes_the_text() {
+        assert_eq!(
+            sha256("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert!(!TosAcceptance::bundled().text_changed());
+
+        let mut edited = TosAcceptance::bundled();
+        edited.tos_sha256 = Some(sha256("older wording"));
+        assert!(edited.text_changed());
+    }
+}
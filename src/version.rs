@@ -0,0 +1,166 @@
+use semver::Version;
+use serde::{de, Deserialize, Deserializer};
+use std::convert::TryFrom;
+
+use crate::constants;
+
+/// Versions used to be plain integers, so the version endpoint and older credentials files can
+/// still hand us one. A bare integer `n` is read as `n.0.0`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RawVersion {
+    Legacy(u64),
+    SemVer(String),
+}
+
+impl TryFrom<RawVersion> for Version {
+    type Error = semver::Error;
+
+    fn try_from(raw: RawVersion) -> Result<Self, Self::Error> {
+        match raw {
+            RawVersion::Legacy(major) => Ok(Version::new(major, 0, 0)),
+            RawVersion::SemVer(version) => Version::parse(&version),
+        }
+    }
+}
+
+pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<Version, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let raw = RawVersion::deserialize(deserializer)?;
+    Version::try_from(raw).map_err(de::Error::custom)
+}
+
+/// The version of the running cli
+pub fn cli_version() -> Version {
+    Version::parse(constants::VERSION).expect("cli-version is not valid semver")
+}
+
+/// The version of the terms of service bundled with this cli
+pub fn tos_version() -> Version {
+    Version::parse(constants::TOS_VERSION).expect("terms-of-service-version is not valid semver")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Update {
+    UpToDate,
+    /// A newer version is available
+    Recommended(Version),
+    /// The running version is older than the minimum version the server still supports
+    Required(Version),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "RawVersionResponse")]
+pub struct VersionResponse {
+    version: Version,
+    minimum_version: Option<Version>,
+    /// Builds from before semver are published under the bare integer they were versioned with
+    artifact: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawVersionResponse {
+    version: RawVersion,
+    #[serde(default)]
+    minimum_version: Option<RawVersion>,
+}
+
+impl TryFrom<RawVersionResponse> for VersionResponse {
+    type Error = semver::Error;
+
+    fn try_from(raw: RawVersionResponse) -> Result<Self, Self::Error> {
+        let legacy = match raw.version {
+            RawVersion::Legacy(major) => Some(major.to_string()),
+            RawVersion::SemVer(_) => None,
+        };
+        let version = Version::try_from(raw.version)?;
+        Ok(VersionResponse {
+            artifact: legacy.unwrap_or_else(|| version.to_string()),
+            version,
+            minimum_version: raw.minimum_version.map(Version::try_from).transpose()?,
+        })
+    }
+}
+
+impl VersionResponse {
+    pub fn latest(&self) -> &Version {
+        &self.version
+    }
+
+    pub fn minimum(&self) -> Option<&Version> {
+        self.minimum_version.as_ref()
+    }
+
+    /// The name the build of the latest version is published under
+    pub fn artifact(&self) -> &str {
+        &self.artifact
+    }
+
+    /// Pre-releases are only recommended to users who are already running a pre-release. Anyone
+    /// below the minimum supported version has to update regardless.
+    pub fn update_for(&self, current: &Version) -> Update {
+        if self.version <= *current {
+            return Update::UpToDate;
+        }
+
+        match &self.minimum_version {
+            Some(minimum) if current < minimum => Update::Required(self.version.clone()),
+            _ if !self.version.pre.is_empty() && current.pre.is_empty() => Update::UpToDate,
+            _ => Update::Recommended(self.version.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(version: &str) -> Version {
+        Version::parse(version).unwrap()
+    }
+
+    #[test]
+    fn bundled_versions_parse() {
+        cli_version();
+        tos_version();
+    }
+
+    #[test]
+    fn legacy_integer_response() {
+        let resp: VersionResponse = serde_json::from_str(r#"{"version": 6}"#).unwrap();
+        assert_eq!(resp.latest(), &v("6.0.0"));
+        assert_eq!(resp.minimum(), None);
+        assert_eq!(resp.artifact(), "6");
+
+        let resp: VersionResponse = serde_json::from_str(r#"{"version": "6.1.0"}"#).unwrap();
+        assert_eq!(resp.artifact(), "6.1.0");
+    }
+
+    #[test]
+    fn update_decisions() {
+        let resp: VersionResponse =
+            serde_json::from_str(r#"{"version": "2.1.0", "minimumVersion": "2.0.0"}"#).unwrap();
+
+        assert_eq!(resp.update_for(&v("2.1.0")), Update::UpToDate);
+        assert_eq!(resp.update_for(&v("3.0.0")), Update::UpToDate);
+        assert_eq!(
+            resp.update_for(&v("2.0.1")),
+            Update::Recommended(v("2.1.0"))
+        );
+        assert_eq!(resp.update_for(&v("1.9.0")), Update::Required(v("2.1.0")));
+    }
+
+    #[test]
+    fn pre_releases_only_for_pre_release_users() {
+        let resp: VersionResponse = serde_json::from_str(r#"{"version": "3.0.0-beta.1"}"#).unwrap();
+
+        assert_eq!(resp.update_for(&v("2.1.0")), Update::UpToDate);
+        assert_eq!(
+            resp.update_for(&v("3.0.0-alpha.2")),
+            Update::Recommended(v("3.0.0-beta.1"))
+        );
+    }
+}
@@ -1,21 +1,21 @@
 use assert_cmd::prelude::*;
 use predicates::prelude::*;
-use std::{env, fs::File, process::Command};
+use std::{fs::File, process::Command};
 
 use activity_insights_cli::{constants, Credentials};
+use semver::Version;
 
 const TOS: &str = include_str!("../terms-of-service");
-const TOS_VERSION: usize = include!("../terms-of-service-version");
+const TOS_VERSION: &str = include_str!("../terms-of-service-version");
 
-#[cfg(unix)] // Can't change the HOME dir on Windows so only run on unix
 #[test]
 fn credentials_flow() {
     let fake_home_dir = tempfile::tempdir().unwrap();
-    env::set_var("HOME", fake_home_dir.path());
 
     // Get denied by TOS
     let mut cmd = Command::cargo_bin("activity-insights").unwrap();
-    cmd.arg("dashboard");
+    cmd.env(constants::HOME_ENV_VAR, fake_home_dir.path())
+        .arg("dashboard");
 
     cmd.assert()
         .failure()
@@ -24,15 +24,13 @@ fn credentials_flow() {
 
     // Accept TOS
     let mut cmd = Command::cargo_bin("activity-insights").unwrap();
-    cmd.arg("accept_tos");
+    cmd.env(constants::HOME_ENV_VAR, fake_home_dir.path())
+        .arg("accept_tos");
     cmd.assert().success();
 
-    let creds_path = fake_home_dir
-        .path()
-        .join(constants::PS_DIR)
-        .join(constants::CRED_FILE_NAME);
+    let creds_path = fake_home_dir.path().join(constants::CRED_FILE_NAME);
 
     let file = File::open(creds_path).unwrap();
     let creds: Credentials = serde_yaml::from_reader(file).unwrap();
-    assert!(creds.has_accepted_latest(TOS_VERSION))
+    assert!(creds.has_accepted_latest(&Version::parse(TOS_VERSION).unwrap()))
 }
@@ -0,0 +1,68 @@
+use assert_cmd::prelude::*;
+use predicates::prelude::*;
+use std::process::Command;
+
+use activity_insights_cli::constants;
+
+#[test]
+fn unknown_commands_are_usage_errors() {
+    let fake_home_dir = tempfile::tempdir().unwrap();
+
+    // Would have been read as a pulse, waiting on stdin
+    let mut cmd = Command::cargo_bin("activity-insights").unwrap();
+    cmd.env(constants::HOME_ENV_VAR, fake_home_dir.path())
+        .arg("pluse");
+    cmd.assert()
+        .failure()
+        .code(constants::USAGE_EXIT_CODE)
+        .stderr(predicate::str::contains("pluse"));
+
+    let mut cmd = Command::cargo_bin("activity-insights").unwrap();
+    cmd.env(constants::HOME_ENV_VAR, fake_home_dir.path())
+        .arg("--help");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("accept_tos"));
+}
+
+#[test]
+fn dry_run_prints_the_requests() {
+    let fake_home_dir = tempfile::tempdir().unwrap();
+    let events = serde_json::json!([{
+        "filePath": "Cargo.toml",
+        "eventType": "typing",
+        "eventDate": 1595868513238i64,
+        "editor": "vim",
+    }]);
+
+    // Not registered and no TOS accepted, nothing is sent
+    let mut cmd = assert_cmd::Command::cargo_bin("activity-insights").unwrap();
+    cmd.env(constants::HOME_ENV_VAR, fake_home_dir.path())
+        .args(&["pulse", "--dry-run"])
+        .write_stdin(events.to_string());
+    let output = cmd.assert().success().get_output().stdout.clone();
+
+    let request: serde_json::Value = serde_json::from_slice(&output).unwrap();
+    let pulses = request["pulses"].as_array().unwrap();
+    assert_eq!(pulses.len(), 1);
+    assert_eq!(pulses[0]["editor"], "vim");
+    assert_eq!(pulses[0]["type"], "typing");
+}
+
+#[test]
+fn version_ignores_a_broken_config() {
+    let fake_home_dir = tempfile::tempdir().unwrap();
+    let config = fake_home_dir.path().join(constants::CONFIG_FILE_NAME);
+    std::fs::write(&config, "[http\n").unwrap();
+
+    for config in &[config, fake_home_dir.path().join("missing.toml")] {
+        let mut cmd = Command::cargo_bin("activity-insights").unwrap();
+        cmd.env(constants::HOME_ENV_VAR, fake_home_dir.path())
+            .arg("version")
+            .arg("--config")
+            .arg(config);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains(constants::VERSION));
+    }
+}